@@ -0,0 +1,435 @@
+use hardware::ppu::{PixelSource, RawPixel};
+use palette::Rgb;
+
+/// Four colors for each of the palettes a DMG game can use, indexed by
+/// shade of gray.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colorization {
+    pub background: [Rgb; 4],
+    pub obj0: [Rgb; 4],
+    pub obj1: [Rgb; 4],
+}
+
+impl Colorization {
+    /// Uses the same colors for the background and the sprites, like a
+    /// real DMG would.
+    pub const fn monochrome(colors: [Rgb; 4]) -> Colorization {
+        Colorization {
+            background: colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+
+    /// The colorization the CGB boot ROM picks for a game. Only games
+    /// published by Nintendo get a dedicated one, everything else gets the
+    /// same palette as Right + A.
+    pub fn for_game(title_checksum: u8, fourth_letter: u8, nintendo: bool) -> Colorization {
+        if !nintendo {
+            return Self::from_combination(0);
+        }
+
+        let index = TITLE_CHECKSUMS
+            .iter()
+            .position(|&c| c == title_checksum)
+            .map(|i| PALETTE_PER_CHECKSUM[i])
+            .or_else(|| {
+                DUPLICATE_CHECKSUMS
+                    .iter()
+                    .position(|&(c, l)| c == title_checksum && l == fourth_letter)
+                    .map(|i| PALETTE_PER_DUPLICATE[i])
+            })
+            .unwrap_or(0);
+
+        Self::from_combination(index)
+    }
+
+    fn from_combination(index: usize) -> Colorization {
+        let (obj0, obj1, background) = PALETTE_COMBINATIONS[index];
+        Colorization {
+            background: palette_at(background),
+            obj0: palette_at(obj0),
+            obj1: palette_at(obj1),
+        }
+    }
+
+    /// The color of a pixel on screen, sprites and background use
+    /// different palettes.
+    pub fn color(&self, pixel: RawPixel) -> Rgb {
+        let shade = ((pixel.palette >> (pixel.color * 2)) & 0b11) as usize;
+        match pixel.source {
+            PixelSource::Background | PixelSource::Window => self.background[shade],
+            PixelSource::Sprite0 => self.obj0[shade],
+            PixelSource::Sprite1 => self.obj1[shade],
+        }
+    }
+}
+
+/// The palettes that can be picked by holding a direction, optionally with
+/// A or B, while the CGB boots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ButtonCombo {
+    pub const ALL: [ButtonCombo; 12] = [
+        ButtonCombo::Up,
+        ButtonCombo::UpA,
+        ButtonCombo::UpB,
+        ButtonCombo::Left,
+        ButtonCombo::LeftA,
+        ButtonCombo::LeftB,
+        ButtonCombo::Down,
+        ButtonCombo::DownA,
+        ButtonCombo::DownB,
+        ButtonCombo::Right,
+        ButtonCombo::RightA,
+        ButtonCombo::RightB,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ButtonCombo::Up => "up",
+            ButtonCombo::UpA => "up_a",
+            ButtonCombo::UpB => "up_b",
+            ButtonCombo::Left => "left",
+            ButtonCombo::LeftA => "left_a",
+            ButtonCombo::LeftB => "left_b",
+            ButtonCombo::Down => "down",
+            ButtonCombo::DownA => "down_a",
+            ButtonCombo::DownB => "down_b",
+            ButtonCombo::Right => "right",
+            ButtonCombo::RightA => "right_a",
+            ButtonCombo::RightB => "right_b",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ButtonCombo> {
+        Self::ALL.iter().cloned().find(|c| c.name() == name)
+    }
+
+    pub fn colorization(self) -> Colorization {
+        Colorization::from_combination(match self {
+            ButtonCombo::Up => 5,
+            ButtonCombo::UpA => 43,
+            ButtonCombo::UpB => 28,
+            ButtonCombo::Left => 48,
+            ButtonCombo::LeftA => 40,
+            ButtonCombo::LeftB => 7,
+            ButtonCombo::Down => 8,
+            ButtonCombo::DownA => 3,
+            ButtonCombo::DownB => 49,
+            ButtonCombo::Right => 1,
+            ButtonCombo::RightA => 0,
+            ButtonCombo::RightB => 6,
+        })
+    }
+}
+
+/// Sum of the bytes of the title, 0x134 to 0x143 in the header.
+pub fn title_checksum(title: &[u8]) -> u8 {
+    title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Converts a CGB color (5 bits per channel, red in the low bits).
+fn rgb555(v: u16) -> Rgb {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    Rgb {
+        r: expand(v & 0x1F),
+        g: expand((v >> 5) & 0x1F),
+        b: expand((v >> 10) & 0x1F),
+    }
+}
+
+fn palette_at(offset: usize) -> [Rgb; 4] {
+    let mut palette = [Rgb { r: 0, g: 0, b: 0 }; 4];
+    for (color, &v) in palette.iter_mut().zip(&COLORS[offset..offset + 4]) {
+        *color = rgb555(v);
+    }
+    palette
+}
+
+/// Title checksums of the games with a dedicated palette.
+const TITLE_CHECKSUMS: [u8; 65] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+];
+
+/// Index in `PALETTE_COMBINATIONS` for each entry of `TITLE_CHECKSUMS`.
+const PALETTE_PER_CHECKSUM: [usize; 65] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39,
+];
+
+/// Checksums shared by more than one game, the fourth letter of the title
+/// tells them apart. The boot ROM stores the letters as the string
+/// "BEFAARBEKEK R-URAR INAILICE R" and cycles through the 14 checksums.
+const DUPLICATE_CHECKSUMS: [(u8, u8); 29] = [
+    (0xB3, b'B'),
+    (0x46, b'E'),
+    (0x28, b'F'),
+    (0xA5, b'A'),
+    (0xC6, b'A'),
+    (0xD3, b'R'),
+    (0x27, b'B'),
+    (0x61, b'E'),
+    (0x18, b'K'),
+    (0x66, b'E'),
+    (0x6A, b'K'),
+    (0xBF, b' '),
+    (0x0D, b'R'),
+    (0xF4, b'-'),
+    (0xB3, b'U'),
+    (0x46, b'R'),
+    (0x28, b'A'),
+    (0xA5, b'R'),
+    (0xC6, b' '),
+    (0xD3, b'I'),
+    (0x27, b'N'),
+    (0x61, b'A'),
+    (0x18, b'I'),
+    (0x66, b'L'),
+    (0x6A, b'I'),
+    (0xBF, b'C'),
+    (0x0D, b'E'),
+    (0xF4, b' '),
+    (0xB3, b'R'),
+];
+
+const PALETTE_PER_DUPLICATE: [usize; 29] = [
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19,
+    34, 23, 18, 29,
+];
+
+/// OBJ0, OBJ1 and BG palettes as offsets in `COLORS`. A few of them don't
+/// start at the beginning of a palette, that's how the boot ROM has them.
+const PALETTE_COMBINATIONS: [(usize, usize, usize); 51] = [
+    (16, 16, 116),
+    (72, 72, 72),
+    (80, 80, 80),
+    (96, 96, 96),
+    (36, 36, 36),
+    (0, 0, 0),
+    (108, 108, 108),
+    (20, 20, 20),
+    (48, 48, 48),
+    (104, 104, 104),
+    (64, 32, 32),
+    (16, 112, 112),
+    (16, 8, 8),
+    (12, 16, 16),
+    (16, 116, 116),
+    (112, 16, 112),
+    (8, 68, 8),
+    (64, 64, 32),
+    (16, 16, 28),
+    (16, 16, 72),
+    (16, 16, 80),
+    (76, 76, 36),
+    (15, 15, 44),
+    (68, 68, 8),
+    (16, 16, 8),
+    (16, 16, 12),
+    (112, 112, 0),
+    (12, 12, 0),
+    (0, 0, 4),
+    (72, 88, 72),
+    (80, 88, 80),
+    (96, 88, 96),
+    (64, 88, 32),
+    (68, 16, 52),
+    (111, 0, 56),
+    (111, 16, 60),
+    (76, 88, 36),
+    (64, 112, 40),
+    (16, 92, 112),
+    (68, 88, 8),
+    (16, 0, 8),
+    (16, 112, 12),
+    (112, 12, 0),
+    (12, 112, 16),
+    (84, 112, 16),
+    (12, 112, 0),
+    (100, 12, 112),
+    (0, 112, 32),
+    (16, 12, 112),
+    (112, 12, 24),
+    (16, 112, 116),
+];
+
+/// The colors stored in the CGB boot ROM, four per palette.
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, //
+    0x639F, 0x4279, 0x15B0, 0x04CB, //
+    0x7FFF, 0x6E31, 0x454A, 0x0000, //
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, //
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, //
+    0x7FFF, 0x5294, 0x294A, 0x0000, //
+    0x7FFF, 0x03FF, 0x012F, 0x0000, //
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, //
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, //
+    0x7E74, 0x03FF, 0x0180, 0x0000, //
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, //
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, //
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, //
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, //
+    0x03ED, 0x7FFF, 0x255F, 0x0000, //
+    0x036A, 0x021F, 0x03FF, 0x7FFF, //
+    0x7FFF, 0x01DF, 0x0112, 0x0000, //
+    0x231F, 0x035F, 0x00F2, 0x0009, //
+    0x7FFF, 0x03EA, 0x011F, 0x0000, //
+    0x299F, 0x001A, 0x000C, 0x0000, //
+    0x7FFF, 0x027F, 0x001F, 0x0000, //
+    0x7FFF, 0x03E0, 0x0206, 0x0120, //
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, //
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, //
+    0x7FFF, 0x03FF, 0x001F, 0x0000, //
+    0x03FF, 0x001F, 0x000C, 0x0000, //
+    0x7FFF, 0x033F, 0x0193, 0x0000, //
+    0x0000, 0x4200, 0x037F, 0x7FFF, //
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, //
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, //
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rgb(v: u32) -> Rgb {
+        Rgb {
+            r: (v >> 16) as u8,
+            g: (v >> 8) as u8,
+            b: v as u8,
+        }
+    }
+
+    fn palette(colors: [u32; 4]) -> [Rgb; 4] {
+        [
+            rgb(colors[0]),
+            rgb(colors[1]),
+            rgb(colors[2]),
+            rgb(colors[3]),
+        ]
+    }
+
+    fn title(name: &str) -> [u8; 16] {
+        let mut title = [0; 16];
+        title[..name.len()].copy_from_slice(name.as_bytes());
+        title
+    }
+
+    #[test]
+    pub fn title_checksums() {
+        assert_eq!(title_checksum(&title("TETRIS")), 0xDB);
+        assert_eq!(title_checksum(&title("POKEMON RED")), 0x14);
+        assert_eq!(title_checksum(&title("SUPER MARIOLAND")), 0x46);
+    }
+
+    #[test]
+    pub fn button_combos() {
+        let red = palette([0xFFFFFF, 0xFF8484, 0x943939, 0x000000]);
+        let green = palette([0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]);
+        let blue = palette([0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]);
+
+        assert_eq!(
+            ButtonCombo::UpA.colorization(),
+            Colorization {
+                background: red,
+                obj0: green,
+                obj1: blue,
+            }
+        );
+        assert_eq!(
+            ButtonCombo::Left.colorization(),
+            Colorization {
+                background: blue,
+                obj0: red,
+                obj1: green,
+            }
+        );
+        assert_eq!(
+            ButtonCombo::RightB.colorization(),
+            Colorization::monochrome(palette([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]))
+        );
+
+        for &combo in ButtonCombo::ALL.iter() {
+            assert_eq!(ButtonCombo::from_name(combo.name()), Some(combo));
+        }
+    }
+
+    #[test]
+    pub fn game_colorization() {
+        let default = ButtonCombo::RightA.colorization();
+        let tetris = title_checksum(&title("TETRIS"));
+
+        assert_eq!(
+            Colorization::for_game(tetris, b'R', true),
+            ButtonCombo::DownA.colorization()
+        );
+        assert_eq!(Colorization::for_game(tetris, b'R', false), default);
+        assert_eq!(Colorization::for_game(0xEE, b' ', true), default);
+
+        // Super Mario Land and Metroid II have the same checksum
+        let mario = Colorization::for_game(0x46, b'E', true);
+        let metroid = Colorization::for_game(0x46, b'R', true);
+        assert_ne!(mario, metroid);
+        assert_eq!(mario.background[0], rgb(0xB5B5FF));
+    }
+
+    #[test]
+    pub fn duplicate_checksums() {
+        let for_title = |name: &str| {
+            Colorization::for_game(title_checksum(&title(name)), name.as_bytes()[3], true)
+        };
+
+        // 0xBF
+        assert_eq!(for_title("KID ICARUS"), Colorization::from_combination(24));
+        assert_eq!(for_title("SOCCER"), Colorization::from_combination(34));
+        // 0xB3
+        assert_eq!(for_title("KIRBY2"), Colorization::from_combination(36));
+        assert_eq!(
+            for_title("TETRIS ATTACK"),
+            Colorization::from_combination(29)
+        );
+        // 0x18
+        assert_eq!(
+            for_title("DONKEYKONGLAND"),
+            Colorization::from_combination(39)
+        );
+        assert_eq!(for_title("WARIO BLAST"), Colorization::from_combination(0));
+    }
+
+    #[test]
+    pub fn pixel_color() {
+        let colorization = ButtonCombo::UpA.colorization();
+        let pixel = RawPixel {
+            color: 1,
+            source: PixelSource::Sprite1,
+            palette: 0b11_10_01_00,
+        };
+        assert_eq!(colorization.color(pixel), rgb(0x63A5FF));
+
+        let pixel = RawPixel {
+            color: 1,
+            source: PixelSource::Window,
+            palette: 0b00_00_11_00,
+        };
+        assert_eq!(colorization.color(pixel), rgb(0x000000));
+    }
+}
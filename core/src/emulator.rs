@@ -1,211 +1,157 @@
-use hardware::apu::NoisePattern;
+use colorization::Colorization;
+use gbs::Gbs;
+use hardware::apu::Channel;
 use hardware::cartridge::Cartridge;
-use hardware::cpu::Cpu;
+use hardware::cpu::{Cpu, HandlerHolder};
 use hardware::handler_holder::GBHandlerHolder;
-
-const VOLUME_MAX: i16 = 32000;
-pub const AUDIO_BUFFER_SIZE: usize = 1470;
+use hardware::ppu::Layer;
+use hardware::serial::LinkPort;
 
 pub struct Emulator {
     pub cpu: Cpu,
-    noise_7_bit: [u8; 127],
-    noise_15_bit: [u8; 32767],
-    phase: Phase,
-    frequency: f64,
+    colorization: Colorization,
 }
 
 impl Emulator {
     pub fn from_data(data: &[u8], frequency: f64) -> Result<Emulator, String> {
-        let handler = GBHandlerHolder::new(Cartridge::from_data(data));
+        let cartridge = Cartridge::from_data(data);
+        let colorization = cartridge.colorization();
+        let mut handler = GBHandlerHolder::new(cartridge);
+        handler.set_sample_rate(frequency);
 
         Ok(Emulator {
             cpu: Cpu::new(Box::new(handler)),
-            frequency: frequency,
-            noise_7_bit: generate_noise_7_bit(),
-            noise_15_bit: generate_noise_15_bit(),
-            phase: Phase {
-                channel_1: 0.0,
-                channel_2: 0.0,
-                channel_3: 0.0,
-                channel_4: 0.0,
-            },
+            colorization,
         })
     }
 
+    /// Plays `track` of a GBS file, from 1 to `song_count`.
+    pub fn from_gbs(gbs: &Gbs, track: u8, frequency: f64) -> Result<Emulator, String> {
+        let rom = gbs.to_rom(track)?;
+        Emulator::from_data(&rom, frequency)
+    }
+
     pub fn reset(&mut self) {
         self.cpu.reset();
     }
 
-    pub fn generate_sound(&mut self) -> [i16; AUDIO_BUFFER_SIZE] {
-        let mut out = [0; AUDIO_BUFFER_SIZE];
-        self.generate_sound_into(&mut out);
-        out
+    /// Lets games access VRAM and OAM in every PPU mode, unlike the real
+    /// hardware. Useful to debug homebrew with bad timings.
+    pub fn set_lenient_access(&mut self, lenient: bool) {
+        self.cpu.handler_holder.set_lenient_access(lenient);
     }
 
-    pub fn generate_sound_into(&mut self, out: &mut [i16]) {
-        let audio = self.cpu.handler_holder.get_audio_buffer();
-
-        let mut i = 0;
-
-        let mut channel_1_phase = self.phase.channel_1;
-        let channel_1_frequency = 131072 / (2048 - audio.sound_1().frequency());
-        let channel_1_phase_inc = channel_1_frequency as f64 / self.frequency;
-        let channel_1_volume =
-            (VOLUME_MAX as f64 * audio.sound_1().volume() as f64 / 16.0 / 4.0) as i16;
-
-        let mut channel_2_phase = self.phase.channel_2;
-        let channel_2_frequency = 131072 / (2048 - audio.sound_2().frequency());
-        let channel_2_phase_inc = channel_2_frequency as f64 / self.frequency;
-        let channel_2_volume =
-            (VOLUME_MAX as f64 * audio.sound_2().volume() as f64 / 16.0 / 4.0) as i16;
-
-        let mut channel_3_phase = self.phase.channel_3;
-        let mut channel_3_pattern = [0.0; 32];
-        for i in 0..16 {
-            channel_3_pattern[i * 2] = ((audio.sound_3().wave_pattern()[i] & 0xF0) >> 4) as f32;
-            channel_3_pattern[i * 2 + 1] = (audio.sound_3().wave_pattern()[i] & 0x0F) as f32;
-        }
-
-        let channel_3_frequency = 65536 / (2048 - audio.sound_3().frequency());
-        let channel_3_volume =
-            VOLUME_MAX as f32 * audio.sound_3().volume().to_volume() as f32 / 4.0;
-        let channel_3_phase_inc = channel_3_frequency as f64 / self.frequency;
-
-        let channel_4_pattern = match audio.sound_4().pattern() {
-            NoisePattern::C15 => &self.noise_15_bit[..],
-            NoisePattern::C7 => &self.noise_7_bit[..],
-        };
-        let mut channel_4_phase = self.phase.channel_4;
-        if channel_4_phase > channel_4_pattern.len() as f64 {
-            // This means we changed pattern, which means the phase is invalid
-            channel_4_phase = 0.0;
-        }
-
-        let channel_4_phase_inc = audio.sound_4().frequency() as f64 / self.frequency;
-        let channel_4_volume =
-            (VOLUME_MAX as f64 * audio.sound_4().volume() as f64 / 16.0 / 4.0) as i16;
-
-        while i < out.len() {
-            let channel_1 = if channel_1_phase < audio.sound_1().wave_duty() as f64 {
-                channel_1_volume
-            } else {
-                -channel_1_volume
-            };
-
-            let channel_2 = if channel_2_phase < audio.sound_2().wave_duty() as f64 {
-                channel_2_volume
-            } else {
-                -channel_2_volume
-            };
-
-            let channel_3_index = (channel_3_phase * 32.0) as usize;
-            let channel_3 =
-                ((channel_3_pattern[channel_3_index] - 7.0) / 8.0 * channel_3_volume) as i16;
-
-            let channel_4_index = channel_4_phase as usize;
-            let channel_4_bit = channel_4_pattern[channel_4_index];
-            let channel_4 = if channel_4_bit == 1 {
-                channel_4_volume
-            } else {
-                -channel_4_volume
-            };
-
-            out[i] = ((if audio.sound_1().playing_left() {
-                channel_1
-            } else {
-                0
-            }) + (if audio.sound_2().playing_left() {
-                channel_2
-            } else {
-                0
-            }) + (if audio.sound_3().playing_left() {
-                channel_3
-            } else {
-                0
-            }) + (if audio.sound_4().playing_left() {
-                channel_4
-            } else {
-                0
-            })) / 4;
-
-            out[i + 1] = ((if audio.sound_1().playing_right() {
-                channel_1
-            } else {
-                0
-            }) + (if audio.sound_2().playing_right() {
-                channel_2
-            } else {
-                0
-            }) + (if audio.sound_3().playing_right() {
-                channel_3
-            } else {
-                0
-            }) + (if audio.sound_4().playing_right() {
-                channel_4
-            } else {
-                0
-            })) / 4;
-
-            channel_1_phase = (channel_1_phase + channel_1_phase_inc).fract();
-            channel_2_phase = (channel_2_phase + channel_2_phase_inc).fract();
-            channel_3_phase = (channel_3_phase + channel_3_phase_inc).fract();
-            channel_4_phase = channel_4_phase + channel_4_phase_inc;
-            while channel_4_phase > channel_4_pattern.len() as f64 {
-                channel_4_phase -= channel_4_pattern.len() as f64;
-            }
-
-            i += 2;
-        }
-
-        self.phase = Phase {
-            channel_1: channel_1_phase,
-            channel_2: channel_2_phase,
-            channel_3: channel_3_phase,
-            channel_4: channel_4_phase,
-        };
+    pub fn layer_visible(&self, layer: Layer) -> bool {
+        self.cpu.handler_holder.render_options().visible(layer)
     }
-}
 
-#[derive(Clone, Copy)]
-pub struct Phase {
-    channel_1: f64,
-    channel_2: f64,
-    channel_3: f64,
-    channel_4: f64,
-}
+    /// Hides or shows one of the layers of the screen, hidden layers don't
+    /// change the timing of the emulation.
+    pub fn set_layer_visible(&mut self, layer: Layer, visible: bool) {
+        let mut options = self.cpu.handler_holder.render_options();
+        options.set_visible(layer, visible);
+        self.cpu.handler_holder.set_render_options(options);
+    }
 
-fn sound_rng(v: u16, generator: u16) -> u16 {
-    let bit = v & 1;
-    let next = v >> 1;
-    if bit != 0 {
-        next ^ generator
-    } else {
-        next
+    pub fn sprite_boxes(&self) -> bool {
+        self.cpu.handler_holder.render_options().sprite_boxes
     }
-}
 
-fn generate_noise_array(out: &mut [u8], generator: u16, bits: u32) {
-    assert!(bits < 16);
+    /// Outlines the sprites on screen.
+    pub fn set_sprite_boxes(&mut self, enabled: bool) {
+        let mut options = self.cpu.handler_holder.render_options();
+        options.sprite_boxes = enabled;
+        self.cpu.handler_holder.set_render_options(options);
+    }
 
-    let mut v = 2u16.pow(bits) - 1;
+    pub fn channel_muted(&self, channel: Channel) -> bool {
+        self.cpu.handler_holder.mix_options().muted[channel.index()]
+    }
 
-    for i in 0..out.len() {
-        out[i] = (v & 1) as u8;
-        v = sound_rng(v, generator);
+    /// Leaves a channel out of the mix, the game can't tell.
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        let mut options = self.cpu.handler_holder.mix_options();
+        options.set_muted(channel, muted);
+        self.cpu.handler_holder.set_mix_options(options);
     }
 
-    // Let's verify that we have reached the end of the cycle
-    assert_eq!(2u16.pow(bits) - 1, v);
-}
+    pub fn solo_channel(&self) -> Option<Channel> {
+        self.cpu.handler_holder.mix_options().solo
+    }
 
-fn generate_noise_7_bit() -> [u8; 127] {
-    let mut pattern = [0; 127];
-    generate_noise_array(&mut pattern, 0b1000001, 7);
-    pattern
-}
+    /// Only plays `channel` in the mix, ignoring the muted channels.
+    pub fn set_solo_channel(&mut self, channel: Option<Channel>) {
+        let mut options = self.cpu.handler_holder.mix_options();
+        options.solo = channel;
+        self.cpu.handler_holder.set_mix_options(options);
+    }
+
+    /// Renders every channel to its own buffer too, see
+    /// `generate_channel_sound_into`.
+    pub fn set_channel_buffers(&mut self, enabled: bool) {
+        let mut options = self.cpu.handler_holder.mix_options();
+        options.channel_buffers = enabled;
+        self.cpu.handler_holder.set_mix_options(options);
+    }
+
+    /// Logs what the game writes to the sound registers, the log can be
+    /// played back by VGM players.
+    pub fn start_vgm_log(&mut self) {
+        self.cpu.handler_holder.start_vgm_log();
+    }
 
-fn generate_noise_15_bit() -> [u8; 32767] {
-    let mut pattern = [0; 32767];
-    generate_noise_array(&mut pattern, 0b100000000000001, 15);
-    pattern
+    /// The VGM file logged since `start_vgm_log`.
+    pub fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        self.cpu.handler_holder.stop_vgm_log()
+    }
+
+    /// Plugs a link cable in, see `LinkCable` to connect two emulators.
+    /// `None` unplugs it.
+    pub fn set_link_port(&mut self, port: Option<Box<dyn LinkPort>>) {
+        self.cpu.handler_holder.set_link_port(port);
+    }
+
+    /// The palettes a CGB would use to colorize this game.
+    pub fn colorization(&self) -> Colorization {
+        self.colorization
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.cpu.handler_holder.sample_rate()
+    }
+
+    /// Can be changed while the game is running, e.g. when the audio
+    /// device changes.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.cpu.handler_holder.set_sample_rate(sample_rate);
+    }
+
+    /// Stretches the output by `factor`, which is clamped to
+    /// `MAX_RATE_ADJUSTMENT` around 1. Frontends nudge it every frame to
+    /// keep their audio buffer half full.
+    pub fn set_rate_adjustment(&mut self, factor: f64) {
+        self.cpu.handler_holder.set_rate_adjustment(factor);
+    }
+
+    /// Every sample produced since the last call, interleaved stereo.
+    /// How many depends on the sample rate and the time emulated, about
+    /// 1470 for a frame at 44100 Hz.
+    pub fn generate_sound(&mut self) -> Vec<i16> {
+        let mut out = vec![0; self.cpu.handler_holder.pending_audio_samples()];
+        self.generate_sound_into(&mut out);
+        out
+    }
+
+    /// Same as `generate_sound` without allocating, returns how many
+    /// samples were copied.
+    pub fn generate_sound_into(&mut self, out: &mut [i16]) -> usize {
+        self.cpu.handler_holder.drain_audio_samples(out)
+    }
+
+    /// Interleaved stereo samples of a single channel, muted or not,
+    /// returns how many were copied. Needs `set_channel_buffers`, nothing
+    /// is copied otherwise.
+    pub fn generate_channel_sound_into(&mut self, channel: Channel, out: &mut [i16]) -> usize {
+        self.cpu.handler_holder.drain_channel_samples(channel, out)
+    }
 }
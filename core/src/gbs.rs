@@ -0,0 +1,301 @@
+const HEADER_SIZE: usize = 0x70;
+const BANK_SIZE: usize = 0x4000;
+
+/// Where the player starts, right after the cartridge header.
+const PLAYER_ADDRESS: u16 = 0x150;
+/// The player and the cartridge header have to fit below the music.
+const MIN_LOAD_ADDRESS: u16 = 0x200;
+
+/// Timer control bit that selects the timer interrupt over VBlank.
+const TAC_TIMER_INTERRUPT: u8 = 0b100;
+
+/**
+ * A GBS file, the music of a game ripped out of the ROM together with
+ * the routines that play it.
+ *
+ * There's no hardware to run these on, the player builds a cartridge that
+ * calls the init routine for a track and then the play routine on every
+ * VBlank or timer interrupt.
+ */
+pub struct Gbs {
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub song_count: u8,
+    /// 1-based, like the tracks passed to `to_rom`.
+    pub first_song: u8,
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    stack_pointer: u16,
+    timer_modulo: u8,
+    timer_control: u8,
+    data: Vec<u8>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+impl Gbs {
+    pub fn from_data(data: &[u8]) -> Result<Gbs, String> {
+        if data.len() < HEADER_SIZE || &data[0..3] != b"GBS" {
+            return Err("Not a GBS file.".to_string());
+        }
+
+        if data[3] != 1 {
+            return Err(format!("Unsupported GBS version {}.", data[3]));
+        }
+
+        let gbs = Gbs {
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: read_u16(data, 0x06),
+            init_address: read_u16(data, 0x08),
+            play_address: read_u16(data, 0x0A),
+            stack_pointer: read_u16(data, 0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: read_string(&data[0x10..0x30]),
+            author: read_string(&data[0x30..0x50]),
+            copyright: read_string(&data[0x50..0x70]),
+            data: data[HEADER_SIZE..].to_vec(),
+        };
+
+        if gbs.song_count == 0 || gbs.first_song == 0 || gbs.first_song > gbs.song_count {
+            return Err(format!(
+                "Invalid GBS song count {} and first song {}.",
+                gbs.song_count, gbs.first_song
+            ));
+        }
+
+        if gbs.load_address < MIN_LOAD_ADDRESS || gbs.load_address >= 0x8000 {
+            return Err(format!(
+                "Unsupported GBS load address ${:04X}.",
+                gbs.load_address
+            ));
+        }
+
+        Ok(gbs)
+    }
+
+    /// Whether the play routine runs on the timer interrupt instead of
+    /// VBlank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & TAC_TIMER_INTERRUPT != 0
+    }
+
+    /** A MBC3 cartridge that plays `track`, from 1 to `song_count`. The
+     * music is banked like in the original game, the bank switching
+     * writes of the play routine go to the MBC. */
+    pub fn to_rom(&self, track: u8) -> Result<Vec<u8>, String> {
+        if track == 0 || track > self.song_count {
+            return Err(format!(
+                "Invalid track {}, this file has {} tracks.",
+                track, self.song_count
+            ));
+        }
+
+        let size = self.load_address as usize + self.data.len();
+        let banks = rom_banks(size);
+        // The ROM size byte only goes up to 128 banks
+        if banks > 128 {
+            return Err("GBS file too big.".to_string());
+        }
+
+        let mut rom = vec![0; banks * BANK_SIZE];
+        rom[self.load_address as usize..size].copy_from_slice(&self.data);
+
+        // The RST vectors are relocated to the start of the music
+        for vector in (0x00..0x40).step_by(8) {
+            write_code(&mut rom, vector, &jp(self.load_address + vector));
+        }
+
+        // The play routine runs on an interrupt
+        let play = [&call(self.play_address)[..], &[0xD9]].concat(); // RETI
+        write_code(&mut rom, 0x40, &play);
+        write_code(&mut rom, 0x50, &play);
+
+        // Entry point
+        write_code(
+            &mut rom,
+            0x100,
+            &[&[0x00][..], &jp(PLAYER_ADDRESS)].concat(),
+        );
+
+        for (i, c) in self.title.bytes().take(15).enumerate() {
+            rom[0x134 + i] = c;
+        }
+        // MBC3 with RAM, most music drivers keep their state there
+        rom[0x147] = 0x13;
+        rom[0x148] = banks.trailing_zeros() as u8 - 1;
+        rom[0x149] = 0x02;
+
+        let interrupt = if self.uses_timer() { 0x04 } else { 0x01 };
+
+        let player = [
+            // DI
+            &[0xF3][..],
+            // LD SP, stack_pointer
+            &[0x31],
+            &self.stack_pointer.to_le_bytes(),
+            // Enable the cartridge RAM
+            &ld_a(0x0A),
+            &[0xEA, 0x00, 0x00],
+            // Turn the APU on with every channel on both sides, like the
+            // boot ROM leaves it
+            &ld_a(0x80),
+            &ldh_a(0x26),
+            &ld_a(0xFF),
+            &ldh_a(0x25),
+            &ld_a(0x77),
+            &ldh_a(0x24),
+            // The timer settings, the double speed bit is for the CGB
+            &ld_a(self.timer_modulo),
+            &ldh_a(0x05),
+            &ldh_a(0x06),
+            &ld_a(self.timer_control & 0b111),
+            &ldh_a(0x07),
+            // The init routine takes the 0-based track in A
+            &ld_a(track - 1),
+            &call(self.init_address),
+            &ld_a(interrupt),
+            &ldh_a(0xFF),
+            // XOR A, clear what's pending since init
+            &[0xAF],
+            &ldh_a(0x0F),
+            // EI
+            &[0xFB],
+            // HALT, JR back to the HALT
+            &[0x76, 0x18, 0xFD],
+        ]
+        .concat();
+        write_code(&mut rom, PLAYER_ADDRESS, &player);
+
+        Ok(rom)
+    }
+}
+
+/// Smallest power of two banks that holds `size` bytes, at least two.
+fn rom_banks(size: usize) -> usize {
+    ((size - 1) / BANK_SIZE + 1).next_power_of_two().max(2)
+}
+
+fn write_code(rom: &mut [u8], address: u16, code: &[u8]) {
+    rom[address as usize..address as usize + code.len()].copy_from_slice(code);
+}
+
+fn jp(address: u16) -> Vec<u8> {
+    [&[0xC3][..], &address.to_le_bytes()].concat()
+}
+
+fn call(address: u16) -> Vec<u8> {
+    [&[0xCD][..], &address.to_le_bytes()].concat()
+}
+
+fn ld_a(v: u8) -> [u8; 2] {
+    [0x3E, v]
+}
+
+/// LDH (0xFF00 + offset), A
+fn ldh_a(offset: u8) -> [u8; 2] {
+    [0xE0, offset]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use emulator::Emulator;
+
+    /// A GBS file with the code at 0x400 and the data after it.
+    fn gbs(code: &[u8], timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        // Three songs, starting from the second
+        data[0x04] = 3;
+        data[0x05] = 2;
+        // Load, init and play
+        data[0x06..0x0C].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x10, 0x04]);
+        // Stack
+        data[0x0C..0x0E].copy_from_slice(&[0xFE, 0xFF]);
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x14].copy_from_slice(b"Test");
+        data.extend_from_slice(code);
+        data
+    }
+
+    fn music() -> Vec<u8> {
+        let mut code = vec![];
+        // init: LD (0xC000), A to keep the track
+        code.extend_from_slice(&[0xEA, 0x00, 0xC0]);
+        // Read the music from bank 2 into 0xC002
+        code.extend_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20]);
+        code.extend_from_slice(&[0xFA, 0x00, 0x40, 0xEA, 0x02, 0xC0]);
+        // RET
+        code.extend_from_slice(&[0xC9, 0x00]);
+        // play at 0x410: count the calls at 0xC001
+        code.extend_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+
+        code.resize(2 * BANK_SIZE - 0x400, 0);
+        code.push(0x42);
+        code
+    }
+
+    fn run_frames(emulator: &mut Emulator, frames: usize) {
+        let mut count = 0;
+        while count < frames {
+            emulator.cpu.next_instruction();
+            if emulator.cpu.handler_holder.should_refresh() {
+                count += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn header() {
+        let gbs = Gbs::from_data(&gbs(&music(), 0, 0)).unwrap();
+        assert_eq!(gbs.title, "Test");
+        assert_eq!(gbs.song_count, 3);
+        assert_eq!(gbs.first_song, 2);
+        assert!(!gbs.uses_timer());
+
+        assert!(gbs.to_rom(0).is_err());
+        assert!(gbs.to_rom(4).is_err());
+        // The data ends in the third bank
+        assert_eq!(gbs.to_rom(1).unwrap().len(), 4 * BANK_SIZE);
+
+        assert!(Gbs::from_data(b"GBR\x01").is_err());
+    }
+
+    #[test]
+    fn play_on_vblank() {
+        let gbs = Gbs::from_data(&gbs(&music(), 0, 0)).unwrap();
+        let mut emulator = Emulator::from_gbs(&gbs, 3, 44100.0).unwrap();
+        run_frames(&mut emulator, 60);
+
+        assert_eq!(emulator.cpu.deref(0xC000), 2);
+        assert_eq!(emulator.cpu.deref(0xC002), 0x42);
+        let calls = emulator.cpu.deref(0xC001);
+        assert!((59..=60).contains(&calls));
+    }
+
+    #[test]
+    fn play_on_timer() {
+        // 4096 Hz divided by 64, a bit more often than VBlank
+        let gbs = Gbs::from_data(&gbs(&music(), 0xC0, 0x04)).unwrap();
+        assert!(gbs.uses_timer());
+
+        let mut emulator = Emulator::from_gbs(&gbs, 1, 44100.0).unwrap();
+        run_frames(&mut emulator, 60);
+
+        assert_eq!(emulator.cpu.deref(0xC000), 0);
+        let calls = emulator.cpu.deref(0xC001);
+        assert!((63..=65).contains(&calls));
+    }
+}
@@ -1,9 +1,33 @@
 /** This file is mostly based on http://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware */
 use bitfield::Bitfield;
+use hardware::blip::BlipBuffer;
 use hardware::cpu;
 use hardware::cpu::Handler;
+use hardware::vgm::VgmLogger;
+use std::cmp;
 use std::convert::From;
 
+/// Clock of the APU in Hz, the channel timers run at this rate.
+pub const CPU_FREQUENCY: f64 = 4194304.0;
+
+/// Output of a single DAC at full volume, with NR50 at its lowest.
+/// Four channels at the highest NR50 volume reach `4 * 8 * VOLUME_UNIT`.
+const VOLUME_UNIT: f32 = 500.0;
+
+/// How much of its charge the output capacitor keeps every T-cycle.
+const CAPACITOR_CHARGE: f64 = 0.999958;
+
+/// How many samples are kept when nobody is reading them, about a second
+/// of stereo audio at the usual rates.
+const MAX_BUFFERED_SAMPLES: usize = 96000;
+
+/// T-cycles between two flushes of the band-limited buffers.
+const OUTPUT_FRAME_CYCLES: u64 = 8192;
+
+/// How far the rate adjustment can move the output rate, the pitch
+/// change is not noticeable below one percent.
+pub const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
 u8_enum! {
     SoundStatus {
         SoundOff = 0b0,
@@ -80,6 +104,16 @@ impl OutputLevel {
         }
     }
 
+    /// How much the wave samples are shifted right before the DAC.
+    fn shift(self) -> u8 {
+        match self {
+            OutputLevel::Mute => 4,
+            OutputLevel::WavePattern => 0,
+            OutputLevel::RightShifted2 => 1,
+            OutputLevel::RightShifted4 => 2,
+        }
+    }
+
     pub fn to_u8(&self) -> u8 {
         match self {
             &OutputLevel::Mute => 0,
@@ -147,6 +181,7 @@ struct Sweep {
 struct SweepWaveDuty {
     volume: u8,
     wave_duty: f32,
+    duty_step: u8,
     shadow_frequency: u64,
     sweep: Sweep,
 }
@@ -218,6 +253,7 @@ impl AudioLine<SweepWaveDuty> {
 struct WaveDuty {
     volume: u8,
     wave_duty: f32,
+    duty_step: u8,
 }
 
 impl TriggerEvent for AudioLine<WaveDuty> {
@@ -244,11 +280,33 @@ impl VolumeSweep for AudioLine<WaveDuty> {
 struct Noise {
     pattern: NoisePattern,
     volume: u8,
+    /// Divisor code from NR43, bits 0-2.
+    ratio: u8,
+    /// Shift clock frequency from NR43, bits 4-7.
+    shift: u8,
+    lfsr: u16,
+}
+
+impl Noise {
+    /** Clocks the linear feedback shift register once.
+     *
+     * The two low bits are XORed, shifted right and the result is put
+     * in bit 14 and, when the width mode is set, in bit 6 too. Changing
+     * the width doesn't reset the register, the next clock just starts
+     * feeding back into bit 6. */
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+        if self.pattern == NoisePattern::C7 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
 }
 
 impl TriggerEvent for AudioLine<Noise> {
     fn trigger_event(&mut self, _: &mut dyn LineMapper) {
-        // TODO:
+        self.sound.lfsr = 0x7FFF;
     }
     fn default_length(&self) -> i64 {
         64
@@ -270,11 +328,17 @@ impl VolumeSweep for AudioLine<Noise> {
 struct Wave {
     wave_pattern: [u8; 16],
     volume: OutputLevel,
+    position: u8,
+    /// The channel read a sample from wave RAM in the last cpu step.
+    just_read: bool,
 }
 
 impl TriggerEvent for AudioLine<Wave> {
     fn trigger_event(&mut self, _: &mut dyn LineMapper) {
-        // TODO:
+        self.sound.position = 0;
+        self.sound.just_read = false;
+        // The first sample is read 3 clocks later than the others
+        self.timer += 6;
     }
     fn default_length(&self) -> i64 {
         256
@@ -284,6 +348,51 @@ impl TriggerEvent for AudioLine<Wave> {
     }
 }
 
+impl AudioLine<Wave> {
+    /** While the channel is playing the CPU can only reach the byte the
+     * channel is reading, and on the DMG only in the same cycle the
+     * channel reads it. */
+    fn wave_ram_index(&self, address: u16) -> Option<usize> {
+        if !self.on {
+            Some(address as usize - 0xFF30)
+        } else if self.sound.just_read {
+            Some(self.sound.position as usize / 2)
+        } else {
+            None
+        }
+    }
+
+    fn read_wave_ram(&self, address: u16) -> u8 {
+        match self.wave_ram_index(address) {
+            Some(index) => self.sound.wave_pattern[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_wave_ram(&mut self, address: u16, v: u8) {
+        if let Some(index) = self.wave_ram_index(address) {
+            self.sound.wave_pattern[index] = v;
+        }
+    }
+
+    /** On the DMG, retriggering the channel right before it reads from
+     * wave RAM corrupts the first bytes with the ones being read. */
+    fn corrupt_wave_ram(&mut self) {
+        if !self.on || self.timer > cpu::CYCLES_PER_STEP as i64 {
+            return;
+        }
+
+        let offset = ((self.sound.position as usize + 1) >> 1) % 16;
+        let pattern = &mut self.sound.wave_pattern;
+        if offset < 4 {
+            pattern[0] = pattern[offset];
+        } else {
+            let start = offset & !3;
+            pattern.copy_within(start..start + 4, 0);
+        }
+    }
+}
+
 impl VolumeSweep for AudioLine<Wave> {
     fn volume(&self) -> u8 {
         self.sound.volume.to_u8()
@@ -303,11 +412,17 @@ struct AudioLine<T> {
     playing_left: bool,
     playing_right: bool,
 
+    /// The DAC of the channel is powered, see `LineMapper::dac_off`.
+    dac: bool,
+
     // Volume sweep stuff
     on: bool,
     counter: i64,
     envelope_counter: i64,
 
+    /// T-cycles until the waveform moves to the next step.
+    timer: i64,
+
     sound: T,
 }
 
@@ -321,9 +436,11 @@ where
             frequency: 0,
             playing_left: false,
             playing_right: false,
+            dac: false,
             on: false,
             counter: 0,
             envelope_counter: 0,
+            timer: 0,
             sound: sound,
         }
     }
@@ -340,6 +457,141 @@ trait TriggerEvent {
     fn turn_off(&mut self);
 }
 
+/** The part of a channel that is clocked by the frequency timer and
+ * produces the actual waveform. */
+trait Oscillator {
+    /// T-cycles between two steps of the waveform.
+    fn period(&self) -> i64;
+    fn step(&mut self);
+    /// Current digital output of the channel, from 0 to 15.
+    fn digital(&self) -> u8;
+}
+
+fn duty_digital(duty_step: u8, wave_duty: f32, volume: u8) -> u8 {
+    if (duty_step as f32) < wave_duty * 8.0 {
+        volume
+    } else {
+        0
+    }
+}
+
+impl Oscillator for AudioLine<SweepWaveDuty> {
+    fn period(&self) -> i64 {
+        (2048 - self.frequency as i64) * 4
+    }
+    fn step(&mut self) {
+        self.sound.duty_step = (self.sound.duty_step + 1) % 8;
+    }
+    fn digital(&self) -> u8 {
+        duty_digital(
+            self.sound.duty_step,
+            self.sound.wave_duty,
+            self.sound.volume,
+        )
+    }
+}
+
+impl Oscillator for AudioLine<WaveDuty> {
+    fn period(&self) -> i64 {
+        (2048 - self.frequency as i64) * 4
+    }
+    fn step(&mut self) {
+        self.sound.duty_step = (self.sound.duty_step + 1) % 8;
+    }
+    fn digital(&self) -> u8 {
+        duty_digital(
+            self.sound.duty_step,
+            self.sound.wave_duty,
+            self.sound.volume,
+        )
+    }
+}
+
+impl Oscillator for AudioLine<Wave> {
+    fn period(&self) -> i64 {
+        (2048 - self.frequency as i64) * 2
+    }
+    fn step(&mut self) {
+        self.sound.position = (self.sound.position + 1) % 32;
+        self.sound.just_read = true;
+    }
+    fn digital(&self) -> u8 {
+        let position = self.sound.position as usize;
+        let byte = self.sound.wave_pattern[position / 2];
+        let sample = if position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+
+        sample >> self.sound.volume.shift()
+    }
+}
+
+impl Oscillator for AudioLine<Noise> {
+    fn period(&self) -> i64 {
+        let divisor = if self.sound.ratio > 0 {
+            self.sound.ratio as i64 * 16
+        } else {
+            8
+        };
+        divisor << self.sound.shift
+    }
+    fn step(&mut self) {
+        // With a shift of 14 or 15 the channel receives no clocks
+        if self.sound.shift < 14 {
+            self.sound.clock_lfsr();
+        }
+    }
+    fn digital(&self) -> u8 {
+        // The output is the inverted low bit of the register
+        if self.sound.lfsr & 1 == 0 {
+            self.sound.volume
+        } else {
+            0
+        }
+    }
+}
+
+/** Runs the frequency timer of a channel for one cpu step, returns
+ * true if the waveform moved. */
+fn update_oscillator<T>(sound: &mut AudioLine<T>) -> bool
+where
+    AudioLine<T>: Oscillator,
+{
+    if !sound.on {
+        return false;
+    }
+
+    let mut stepped = false;
+
+    sound.timer -= cpu::CYCLES_PER_STEP as i64;
+    while sound.timer <= 0 {
+        sound.timer += sound.period();
+        sound.step();
+        stepped = true;
+    }
+
+    stepped
+}
+
+/** Left and right output of a channel out of its DAC, from -1.0 to 1.0.
+ * A DAC that is off outputs 0 regardless of the channel. */
+fn channel_output<T>(sound: &AudioLine<T>) -> (f32, f32)
+where
+    AudioLine<T>: Oscillator,
+{
+    if !sound.dac || (!sound.playing_left && !sound.playing_right) {
+        return (0.0, 0.0);
+    }
+
+    let analog = sound.digital() as f32 / 7.5 - 1.0;
+    (
+        if sound.playing_left { analog } else { 0.0 },
+        if sound.playing_right { analog } else { 0.0 },
+    )
+}
+
 struct GbAudioBuffer {
     sound_1: AudioLine<SweepWaveDuty>,
     sound_2: AudioLine<WaveDuty>,
@@ -469,11 +721,218 @@ impl AudioBuffer for GbAudioBuffer {
     }
 }
 
+impl GbAudioBuffer {
+    fn outputs(&self) -> [(f32, f32); 4] {
+        [
+            channel_output(&self.sound_1),
+            channel_output(&self.sound_2),
+            channel_output(&self.sound_3),
+            channel_output(&self.sound_4),
+        ]
+    }
+}
+
+/// The four sound channels, in register order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Square1,
+        Channel::Square2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Square1 => "square1",
+            Channel::Square2 => "square2",
+            Channel::Wave => "wave",
+            Channel::Noise => "noise",
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            Channel::Square1 => 0,
+            Channel::Square2 => 1,
+            Channel::Wave => 2,
+            Channel::Noise => 3,
+        }
+    }
+}
+
+/// Which channels end up in the mix. Unlike the NR51 panning these don't
+/// change what the game sees, they're meant for listening to the music.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MixOptions {
+    pub muted: [bool; 4],
+    /// When set, only this channel is heard.
+    pub solo: Option<Channel>,
+    /// Also renders each channel to its own sample buffer.
+    pub channel_buffers: bool,
+}
+
+impl MixOptions {
+    pub fn new() -> MixOptions {
+        MixOptions {
+            muted: [false; 4],
+            solo: None,
+            channel_buffers: false,
+        }
+    }
+
+    pub fn audible(&self, channel: Channel) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.muted[channel.index()],
+        }
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+    }
+}
+
+impl Default for MixOptions {
+    fn default() -> MixOptions {
+        MixOptions::new()
+    }
+}
+
+/** The capacitor between the mixer and the output jack of the DMG,
+ * it slowly charges to the DC level of the signal and removes it. */
+struct HighPass {
+    capacitor: f32,
+    /// How much of its charge the capacitor keeps every output sample.
+    charge: f32,
+}
+
+impl HighPass {
+    fn new(sample_rate: f64) -> HighPass {
+        HighPass {
+            capacitor: 0.0,
+            charge: HighPass::charge(sample_rate),
+        }
+    }
+
+    fn charge(sample_rate: f64) -> f32 {
+        CAPACITOR_CHARGE.powf(CPU_FREQUENCY / sample_rate) as f32
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.charge = HighPass::charge(sample_rate);
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge;
+        output
+    }
+}
+
+/// Band-limited stereo output, fed with the amplitude every time it
+/// changes.
+struct StereoOutput {
+    amplitude: (f32, f32),
+    left: BlipBuffer,
+    right: BlipBuffer,
+    high_pass: (HighPass, HighPass),
+    /// Interleaved stereo samples produced since the last drain.
+    samples: Vec<i16>,
+}
+
+fn to_sample(amplitude: f32) -> i16 {
+    amplitude.round().max(i16::MIN as f32).min(i16::MAX as f32) as i16
+}
+
+impl StereoOutput {
+    fn new(sample_rate: f64) -> StereoOutput {
+        StereoOutput {
+            amplitude: (0.0, 0.0),
+            left: BlipBuffer::new(CPU_FREQUENCY, sample_rate),
+            right: BlipBuffer::new(CPU_FREQUENCY, sample_rate),
+            high_pass: (HighPass::new(sample_rate), HighPass::new(sample_rate)),
+            samples: vec![],
+        }
+    }
+
+    fn update(&mut self, clock: u64, amplitude: (f32, f32)) {
+        if amplitude.0 != self.amplitude.0 {
+            self.left.add_delta(clock, amplitude.0 - self.amplitude.0);
+        }
+        if amplitude.1 != self.amplitude.1 {
+            self.right.add_delta(clock, amplitude.1 - self.amplitude.1);
+        }
+
+        self.amplitude = amplitude;
+    }
+
+    /** Only valid right after a flush, the samples already produced
+     * are kept. */
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.left.set_rates(CPU_FREQUENCY, sample_rate);
+        self.right.set_rates(CPU_FREQUENCY, sample_rate);
+        self.high_pass.0.set_sample_rate(sample_rate);
+        self.high_pass.1.set_sample_rate(sample_rate);
+    }
+
+    /** Turns everything stamped until `clock` into output samples. */
+    fn flush(&mut self, clock: u64) {
+        self.left.end_frame(clock);
+        self.right.end_frame(clock);
+
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES * 2 {
+            // Nobody is reading the samples, drop the oldest ones
+            let excess = self.samples.len() - MAX_BUFFERED_SAMPLES;
+            self.samples.drain(..excess);
+        }
+
+        while let (Some(left), Some(right)) = (self.left.read_sample(), self.right.read_sample()) {
+            self.samples.push(to_sample(self.high_pass.0.filter(left)));
+            self.samples.push(to_sample(self.high_pass.1.filter(right)));
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn drain(&mut self, out: &mut [i16]) -> usize {
+        let count = cmp::min(out.len(), self.samples.len());
+        out[..count].copy_from_slice(&self.samples[..count]);
+        self.samples.drain(..count);
+        count
+    }
+}
+
 pub struct SoundController {
     master_status: bool,
     mapper: SoundMemoryMapper,
     buffer: GbAudioBuffer,
     frame_sequencer: FrameSequencer,
+    sample_rate: f64,
+    /// Factor applied to `sample_rate`, see `set_rate_adjustment`.
+    rate_adjustment: f64,
+    /// T-cycles since the band-limited buffers were last flushed.
+    output_clock: u64,
+    /// The mix has to be computed again, something changed since the
+    /// last cpu step.
+    output_dirty: bool,
+    mix_options: MixOptions,
+    output: StereoOutput,
+    /// One output for each channel when `channel_buffers` is on.
+    channel_outputs: Vec<StereoOutput>,
+    /// What reached each register from 0xFF10 to 0xFF3F, reads don't
+    /// return the write-only bits.
+    registers: [u8; 0x30],
+    vgm_logger: Option<VgmLogger>,
 }
 
 impl WavePattern {
@@ -701,7 +1160,8 @@ fn update_dac<T>(line: &mut dyn LineMapper, sound: &mut AudioLine<T>)
 where
     AudioLine<T>: TriggerEvent,
 {
-    if line.dac_off() {
+    sound.dac = !line.dac_off();
+    if !sound.dac {
         sound.turn_off();
     }
 }
@@ -764,7 +1224,7 @@ fn trigger_event<T>(
     line_mapper: &mut dyn LineMapper,
     frame_sequencer: &FrameSequencer,
 ) where
-    AudioLine<T>: TriggerEvent + VolumeSweep,
+    AudioLine<T>: TriggerEvent + VolumeSweep + Oscillator,
 {
     sound.on = true;
     update_playing(line_mapper, sound);
@@ -781,6 +1241,11 @@ fn trigger_event<T>(
     sound.set_volume(line_mapper.initial_volume());
     sound.envelope_counter = line_mapper.envelope_sweep() as i64;
 
+    // The frequency timer is reloaded with the current period, the
+    // frequency might have been written together with the trigger.
+    update_frequency(line_mapper, sound);
+    sound.timer = sound.period();
+
     // Channel specific logic
     sound.trigger_event(line_mapper);
 
@@ -813,7 +1278,7 @@ fn write_nrx4<T>(
     address: u16,
     v: u8,
 ) where
-    AudioLine<T>: TriggerEvent + VolumeSweep,
+    AudioLine<T>: TriggerEvent + VolumeSweep + Oscillator,
 {
     let turns_length_on = !mapper.consecutive() && v & 0b01000000 > 0;
 
@@ -927,6 +1392,7 @@ impl SoundController {
                     SweepWaveDuty {
                         volume: 0,
                         wave_duty: 0.5,
+                        duty_step: 0,
                         shadow_frequency: 0,
                         sweep: Sweep {
                             counter: 0,
@@ -941,6 +1407,7 @@ impl SoundController {
                     WaveDuty {
                         volume: 0,
                         wave_duty: 0.5,
+                        duty_step: 0,
                     },
                 ),
                 sound_3: AudioLine::new(
@@ -948,6 +1415,8 @@ impl SoundController {
                     Wave {
                         volume: OutputLevel::Mute,
                         wave_pattern: [0; 16],
+                        position: 0,
+                        just_read: false,
                     },
                 ),
                 sound_4: AudioLine::new(
@@ -955,33 +1424,238 @@ impl SoundController {
                     Noise {
                         volume: 0,
                         pattern: NoisePattern::C7,
+                        ratio: 0,
+                        shift: 0,
+                        lfsr: 0x7FFF,
                     },
                 ),
             },
+            sample_rate: 44100.0,
+            rate_adjustment: 1.0,
+            output_clock: 0,
+            output_dirty: false,
+            mix_options: MixOptions::new(),
+            output: StereoOutput::new(44100.0),
+            channel_outputs: vec![],
+            registers: [0; 0x30],
+            vgm_logger: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /** Can be changed at any time, the samples already produced stay
+     * at the old rate. */
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.update_output_rate();
+    }
+
+    pub fn rate_adjustment(&self) -> f64 {
+        self.rate_adjustment
+    }
+
+    /** Produces slightly more or less samples than `sample_rate` asks
+     * for, so that hosts can keep the level of their audio buffer steady.
+     * The factor is clamped to `MAX_RATE_ADJUSTMENT` around 1. */
+    pub fn set_rate_adjustment(&mut self, factor: f64) {
+        self.rate_adjustment = factor.clamp(1.0 - MAX_RATE_ADJUSTMENT, 1.0 + MAX_RATE_ADJUSTMENT);
+        self.update_output_rate();
+    }
+
+    fn output_rate(&self) -> f64 {
+        self.sample_rate * self.rate_adjustment
+    }
+
+    fn update_output_rate(&mut self) {
+        // Everything stamped so far is converted at the old rate
+        self.flush_output();
+
+        let rate = self.output_rate();
+        self.output.set_sample_rate(rate);
+        for output in self.channel_outputs.iter_mut() {
+            output.set_sample_rate(rate);
+        }
+    }
+
+    pub fn mix_options(&self) -> MixOptions {
+        self.mix_options
+    }
+
+    pub fn set_mix_options(&mut self, options: MixOptions) {
+        let channel_buffers_changed = options.channel_buffers != self.mix_options.channel_buffers;
+        self.mix_options = options;
+        self.output_dirty = true;
+
+        if channel_buffers_changed {
+            self.reset_channel_outputs();
         }
     }
 
+    /** Starts the channel outputs again from silence, the mix carries on
+     * undisturbed. */
+    fn reset_channel_outputs(&mut self) {
+        // The new outputs start where the mix is
+        self.flush_output();
+
+        let rate = self.output_rate();
+        self.channel_outputs = if self.mix_options.channel_buffers {
+            Channel::ALL
+                .iter()
+                .map(|_| StereoOutput::new(rate))
+                .collect()
+        } else {
+            vec![]
+        };
+        self.output_dirty = true;
+    }
+
+    /** How many samples can be drained right now. */
+    pub fn pending_samples(&mut self) -> usize {
+        self.flush_output();
+        self.output.pending()
+    }
+
+    /** Moves the oldest samples into `out`, returns how many were copied. */
+    pub fn drain_samples(&mut self, out: &mut [i16]) -> usize {
+        self.flush_output();
+        self.output.drain(out)
+    }
+
+    /** Same as `drain_samples` for a single channel, regardless of it
+     * being muted. Nothing is copied unless `channel_buffers` is on. */
+    pub fn drain_channel_samples(&mut self, channel: Channel, out: &mut [i16]) -> usize {
+        self.flush_output();
+        match self.channel_outputs.get_mut(channel.index()) {
+            Some(output) => output.drain(out),
+            None => 0,
+        }
+    }
+
+    /** Starts logging the register writes, the current state of the APU
+     * is written first so the log can start at any time. */
+    pub fn start_vgm_log(&mut self) {
+        let mut logger = VgmLogger::new();
+
+        logger.write(0xFF26, if self.master_status { 0x80 } else { 0x00 });
+        // Wave RAM can only be written with the channel off
+        logger.write(0xFF1A, 0x00);
+        for address in 0xFF30..0xFF40 {
+            logger.write(
+                address,
+                self.buffer.sound_3.sound.wave_pattern[address as usize - 0xFF30],
+            );
+        }
+
+        if self.master_status {
+            let playing = [
+                (0xFF14, self.buffer.sound_1.on),
+                (0xFF19, self.buffer.sound_2.on),
+                (0xFF1E, self.buffer.sound_3.on),
+                (0xFF23, self.buffer.sound_4.on),
+            ];
+
+            for address in 0xFF10..0xFF26 {
+                let mut v = self.registers[address as usize - 0xFF10];
+                if let Some(&(_, on)) = playing.iter().find(|&&(a, _)| a == address) {
+                    // Start again the notes that are playing
+                    v = if on { v | 0x80 } else { v & 0x7F };
+                }
+                logger.write(address, v);
+            }
+        }
+
+        self.vgm_logger = Some(logger);
+    }
+
+    /** Returns the VGM file logged since `start_vgm_log`. */
+    pub fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        self.vgm_logger.take().map(|logger| logger.finish())
+    }
+
     pub fn get_audio(&self) -> &dyn AudioBuffer {
         &self.buffer
     }
 
     pub fn cpu_step(&mut self) {
-        if !self.master_status {
-            return;
+        if self.master_status {
+            if let Some(ev) = self.frame_sequencer.cpu_step() {
+                self.output_dirty = true;
+                match ev {
+                    SequencerEvent::Length => self.update_length(),
+                    SequencerEvent::LengthSweep => {
+                        self.update_length();
+                        self.update_sweep();
+                    }
+                    SequencerEvent::Volume => self.update_volume(),
+                }
+            }
+
+            self.buffer.sound_3.sound.just_read = false;
+
+            // Not short-circuiting, all the timers have to run
+            self.output_dirty |= update_oscillator(&mut self.buffer.sound_1)
+                | update_oscillator(&mut self.buffer.sound_2)
+                | update_oscillator(&mut self.buffer.sound_3)
+                | update_oscillator(&mut self.buffer.sound_4);
         }
 
-        if let Some(ev) = self.frame_sequencer.cpu_step() {
-            match ev {
-                SequencerEvent::Length => self.update_length(),
-                SequencerEvent::LengthSweep => {
-                    self.update_length();
-                    self.update_sweep();
+        // Even when the APU is off the output keeps going, it's just silent
+        self.update_output();
+
+        if let Some(ref mut logger) = self.vgm_logger {
+            logger.cpu_step();
+        }
+    }
+
+    /** Stamps the changes in the mix into the band-limited buffers. */
+    fn update_output(&mut self) {
+        if self.output_dirty {
+            // NR50 scales each side from 1 to 8, VIN would mix in the audio
+            // from the cartridge but none of the supported ones have any.
+            let volume = (
+                (self.mapper.so1_volume() as f32 + 1.0) * VOLUME_UNIT,
+                (self.mapper.so2_volume() as f32 + 1.0) * VOLUME_UNIT,
+            );
+
+            let outputs = self.buffer.outputs();
+
+            let mut mix = (0.0, 0.0);
+            for (i, &channel) in Channel::ALL.iter().enumerate() {
+                let output = outputs[i];
+                let output = (output.0 * volume.0, output.1 * volume.1);
+
+                if self.mix_options.audible(channel) {
+                    mix.0 += output.0;
+                    mix.1 += output.1;
+                }
+
+                if let Some(channel_output) = self.channel_outputs.get_mut(i) {
+                    channel_output.update(self.output_clock, output);
                 }
-                SequencerEvent::Volume => self.update_volume(),
             }
+            self.output.update(self.output_clock, mix);
+
+            self.output_dirty = false;
+        }
+
+        self.output_clock += cpu::CYCLES_PER_STEP as u64;
+        if self.output_clock >= OUTPUT_FRAME_CYCLES {
+            self.flush_output();
         }
     }
 
+    /** Turns everything stamped so far into output samples. */
+    fn flush_output(&mut self) {
+        self.output.flush(self.output_clock);
+        for output in self.channel_outputs.iter_mut() {
+            output.flush(self.output_clock);
+        }
+        self.output_clock = 0;
+    }
+
     fn update_sweep(&mut self) {
         let period = self.mapper.sound_1_sweep_period() as i64;
 
@@ -1066,10 +1740,10 @@ impl SoundController {
             }
             0xFF22 => {
                 channel_4!(self, update_frequency);
-                self.buffer.sound_4.sound.pattern = self.mapper.sound_4_step();
-            }
-            0xFF24 => {
-                // TODO: handle all channels
+                let noise = &mut self.buffer.sound_4.sound;
+                noise.pattern = self.mapper.sound_4_step();
+                noise.ratio = self.mapper.sound_4_ratio();
+                noise.shift = self.mapper.sound_4_shift_clock();
             }
             0xFF25 => {
                 all_channels!(self, update_playing);
@@ -1108,9 +1782,15 @@ impl SoundController {
                 match address {
                     // NR52 and length are not affected by power in the DMG
                     0xFF26 | 0xFF1B | 0xFF20 => {}
-                    0xFF11 => *self.mapper.sound_1_wave_pattern &= 0b00111111,
-                    0xFF16 => *self.mapper.sound_2_wave_pattern &= 0b00111111,
-                    _ => self.write(address, 0x00),
+                    0xFF11 => {
+                        *self.mapper.sound_1_wave_pattern &= 0b00111111;
+                        self.registers[0x01] &= 0b00111111;
+                    }
+                    0xFF16 => {
+                        *self.mapper.sound_2_wave_pattern &= 0b00111111;
+                        self.registers[0x06] &= 0b00111111;
+                    }
+                    _ => self.write_register(address, 0x00),
                 }
             }
         } else {
@@ -1150,12 +1830,29 @@ impl Handler for SoundController {
 
                 v | 0b01110000
             }
-            0xFF30..=0xFF3F => self.buffer.sound_3.sound.wave_pattern[address as usize - 0xFF30],
+            0xFF30..=0xFF3F => self.buffer.sound_3.read_wave_ram(address),
             _ => self.mapper.read(address),
         }
     }
 
-    fn write(&mut self, address: u16, mut v: u8) {
+    fn write(&mut self, address: u16, v: u8) {
+        // 0xFF09-0xFF0F come here too but aren't connected to anything
+        if address < 0xFF10 {
+            return;
+        }
+
+        if let Some(ref mut logger) = self.vgm_logger {
+            logger.write(address, v);
+        }
+
+        self.write_register(address, v);
+    }
+}
+
+impl SoundController {
+    fn write_register(&mut self, address: u16, mut v: u8) {
+        self.output_dirty = true;
+
         if !self.master_status && address != 0xFF26 {
             let mask = match address {
                 // Only writes to NR52 and sound registers are allowed in the DMG
@@ -1165,12 +1862,16 @@ impl Handler for SoundController {
                 0xFF16 => 0b00111111,
                 0xFF1B => 0b11111111,
                 0xFF20 => 0b11111111,
+                // Wave RAM is not part of the APU registers
+                0xFF30..=0xFF3F => 0b11111111,
                 _ => return,
             };
 
             v &= mask;
         }
 
+        self.registers[address as usize - 0xFF10] = v;
+
         match address {
             0xFF10 => {
                 if v & 0b00001000 == 0
@@ -1207,6 +1908,9 @@ impl Handler for SoundController {
                 );
             }
             0xFF1E => {
+                if v & 0b10000000 > 0 {
+                    self.buffer.sound_3.corrupt_wave_ram();
+                }
                 write_nrx4(
                     &mut self.buffer.sound_3,
                     &mut Line3Mapper {
@@ -1229,9 +1933,7 @@ impl Handler for SoundController {
                 );
             }
             0xFF26 => self.write_nr52(v),
-            0xFF30..=0xFF3F => {
-                self.buffer.sound_3.sound.wave_pattern[address as usize - 0xFF30] = v
-            }
+            0xFF30..=0xFF3F => self.buffer.sound_3.write_wave_ram(address, v),
             _ => {
                 self.mapper.write(address, v);
             }
@@ -1344,3 +2046,343 @@ memory_mapper! {
         ],
     },
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(apu: &mut SoundController, cycles: usize) -> Vec<i16> {
+        for _ in 0..cycles / cpu::CYCLES_PER_STEP {
+            apu.cpu_step();
+        }
+
+        let mut out = vec![0; MAX_BUFFERED_SAMPLES];
+        let count = apu.drain_samples(&mut out);
+        out.truncate(count);
+        out
+    }
+
+    fn left(samples: &[i16]) -> Vec<i16> {
+        samples.iter().step_by(2).cloned().collect()
+    }
+
+    fn swing(samples: &[i16]) -> i16 {
+        samples.iter().max().unwrap() - samples.iter().min().unwrap()
+    }
+
+    #[test]
+    fn register_writes_within_a_frame() {
+        let mut apu = SoundController::new();
+        apu.set_sample_rate(32768.0);
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF25, 0x02);
+        // 50% duty, full volume at 1024 Hz
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0x80);
+        apu.write(0xFF19, 0x87);
+
+        let loud = run(&mut apu, 8192);
+        // Lower the volume halfway through the frame
+        apu.write(0xFF17, 0x30);
+        apu.write(0xFF19, 0x87);
+        let quiet = run(&mut apu, 8192);
+
+        assert_eq!(loud.len(), 128);
+        assert_eq!(quiet.len(), 128);
+        // The last period of each half, the high-pass filter barely
+        // changes the shape of the wave within one period.
+        let loud_swing = swing(&left(&loud[64..])) as f32;
+        let quiet_swing = swing(&left(&quiet[64..])) as f32;
+        assert!(loud_swing > 950.0 && loud_swing < 1200.0);
+        assert!(quiet_swing > 180.0 && quiet_swing < 260.0);
+        // Only the left output is on
+        assert!(loud.iter().skip(1).step_by(2).all(|&s| s == 0));
+    }
+
+    #[test]
+    fn master_volume_without_dc() {
+        let mut apu = SoundController::new();
+        apu.set_sample_rate(32768.0);
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF25, 0x11);
+        apu.write(0xFF24, 0x70);
+        // 25% duty, full volume at 1024 Hz
+        apu.write(0xFF11, 0x40);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x80);
+        apu.write(0xFF14, 0x87);
+
+        // Let the capacitor charge
+        let samples = run(&mut apu, 8192 * 40);
+        let last = &samples[samples.len() - 64..];
+        let (so1, so2) = (left(last), left(&last[1..]));
+
+        // No DC bias once the capacitor is charged
+        let mean = |samples: &[i16]| samples.iter().map(|&s| s as i32).sum::<i32>() / 32;
+        assert!(mean(&so1).abs() < 20);
+        assert!(mean(&so2).abs() < 150);
+
+        // SO2 is at 8 times the volume of SO1
+        let ratio = swing(&so2) as f32 / swing(&so1) as f32;
+        assert!(ratio > 7.9 && ratio < 8.1);
+    }
+
+    #[test]
+    fn silent_when_off() {
+        let mut apu = SoundController::new();
+        apu.set_sample_rate(32768.0);
+
+        let samples = run(&mut apu, 8192);
+        assert_eq!(samples.len(), 128);
+        assert!(samples.iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn rate_adjustment() {
+        let mut apu = SoundController::new();
+        apu.set_sample_rate(32768.0);
+        assert_eq!(run(&mut apu, 8192).len(), 128);
+
+        // Clamped to half a percent
+        apu.set_rate_adjustment(1.1);
+        assert_eq!(apu.rate_adjustment(), 1.005);
+        let count = run(&mut apu, 8192 * 16).len() as i32;
+        assert!((count - 2058).abs() <= 2);
+
+        apu.set_rate_adjustment(0.995);
+        let count = run(&mut apu, 8192 * 16).len() as i32;
+        assert!((count - 2038).abs() <= 2);
+    }
+
+    #[test]
+    fn sample_rate_change_without_click() {
+        let mut apu = SoundController::new();
+        apu.set_sample_rate(32768.0);
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF25, 0x02);
+        // 50% duty, full volume at 1024 Hz
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0x80);
+        apu.write(0xFF19, 0x87);
+
+        // Let the capacitor charge
+        let before = run(&mut apu, 8192 * 40);
+        let before = left(&before[before.len() - 64..]);
+
+        apu.set_sample_rate(65536.0);
+        let after = run(&mut apu, 8192);
+        assert_eq!(after.len(), 256);
+
+        // The capacitor keeps its charge, no DC jump after the change
+        let after = left(&after);
+        let mean =
+            |samples: &[i16]| samples.iter().map(|&s| s as i32).sum::<i32>() / samples.len() as i32;
+        assert!(mean(&after[..64]).abs() < 20);
+        assert!((swing(&after) - swing(&before)).abs() < 20);
+    }
+
+    fn noise(pattern: NoisePattern) -> Noise {
+        Noise {
+            pattern,
+            volume: 0,
+            ratio: 0,
+            shift: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn output(noise: &mut Noise, clocks: usize) -> Vec<u16> {
+        (0..clocks)
+            .map(|_| {
+                noise.clock_lfsr();
+                noise.lfsr & 1
+            })
+            .collect()
+    }
+
+    #[test]
+    fn noise_lfsr() {
+        let mut wide = noise(NoisePattern::C15);
+        output(&mut wide, 32767);
+        assert_eq!(wide.lfsr, 0x7FFF);
+
+        let mut narrow = noise(NoisePattern::C7);
+        let first = output(&mut narrow, 127);
+        let second = output(&mut narrow, 127);
+        assert_eq!(first, second);
+        assert!(first.contains(&0) && first.contains(&1));
+    }
+
+    #[test]
+    fn noise_width_change_keeps_state() {
+        let mut noise = noise(NoisePattern::C15);
+        output(&mut noise, 1000);
+        let lfsr = noise.lfsr;
+
+        noise.pattern = NoisePattern::C7;
+        assert_eq!(noise.lfsr, lfsr);
+
+        // The low bits carry on from where the 15-bit sequence was
+        let feedback = (lfsr ^ (lfsr >> 1)) & 1;
+        noise.clock_lfsr();
+        assert_eq!(noise.lfsr & 0x3F, (lfsr >> 1) & 0x3F);
+        assert_eq!((noise.lfsr >> 6) & 1, feedback);
+    }
+
+    #[test]
+    fn noise_trigger_resets_lfsr() {
+        let mut apu = SoundController::new();
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF22, 0x00);
+        apu.write(0xFF23, 0x80);
+        run(&mut apu, 4096);
+        assert!(apu.buffer.sound_4.sound.lfsr != 0x7FFF);
+
+        apu.write(0xFF23, 0x80);
+        assert_eq!(apu.buffer.sound_4.sound.lfsr, 0x7FFF);
+    }
+
+    fn two_squares() -> SoundController {
+        let mut apu = SoundController::new();
+        apu.set_sample_rate(32768.0);
+
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF25, 0x33);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0x00);
+        apu.write(0xFF19, 0x86);
+        apu
+    }
+
+    /** Compares the changes from one sample to the next, the outputs
+     * don't share the charge of their capacitors. */
+    fn same_changes(a: &[i16], b: &[i16]) -> bool {
+        let changes =
+            |samples: &[i16]| -> Vec<i16> { samples.windows(3).map(|w| w[2] - w[0]).collect() };
+
+        changes(a)
+            .iter()
+            .zip(changes(b).iter())
+            .skip(32)
+            .all(|(a, b)| (a - b).abs() <= 3)
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut apu = two_squares();
+        let mut options = MixOptions::new();
+        options.channel_buffers = true;
+        apu.set_mix_options(options);
+
+        let both = run(&mut apu, 8192);
+        let mut square_1 = vec![0; 128];
+        let mut square_2 = vec![0; 128];
+        assert_eq!(
+            apu.drain_channel_samples(Channel::Square1, &mut square_1),
+            128
+        );
+        assert_eq!(
+            apu.drain_channel_samples(Channel::Square2, &mut square_2),
+            128
+        );
+
+        // The mix is the sum of the channel buffers
+        for i in 0..128 {
+            assert!((both[i] - square_1[i] - square_2[i]).abs() <= 2);
+        }
+
+        options.set_muted(Channel::Square2, true);
+        apu.set_mix_options(options);
+        let muted = run(&mut apu, 8192);
+        apu.drain_channel_samples(Channel::Square1, &mut square_1);
+        // Muted channels still have their own buffer
+        assert_eq!(
+            apu.drain_channel_samples(Channel::Square2, &mut square_2),
+            128
+        );
+        assert!(square_2.iter().any(|&s| s != 0));
+        assert!(same_changes(&muted, &square_1));
+
+        // Solo wins over mute
+        options.solo = Some(Channel::Square2);
+        apu.set_mix_options(options);
+        let solo = run(&mut apu, 8192);
+        apu.drain_channel_samples(Channel::Square2, &mut square_2);
+        assert!(same_changes(&solo, &square_2));
+    }
+
+    /** The register writes in a VGM file, in order. */
+    fn vgm_writes(file: &[u8]) -> Vec<(u16, u8)> {
+        let mut writes = vec![];
+        let mut i = 0x100;
+        loop {
+            match file[i] {
+                0x61 => i += 3,
+                0x62 | 0x63 | 0x70..=0x7F => i += 1,
+                0xB3 => {
+                    writes.push((file[i + 1] as u16 + 0xFF10, file[i + 2]));
+                    i += 3;
+                }
+                0x66 => return writes,
+                command => panic!("Unexpected VGM command {:02X}", command),
+            }
+        }
+    }
+
+    #[test]
+    fn vgm_log() {
+        let mut apu = two_squares();
+        // Square 1 stops playing
+        apu.write(0xFF12, 0x00);
+        run(&mut apu, 8192);
+
+        apu.start_vgm_log();
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF26, 0x00);
+        let writes = vgm_writes(&apu.stop_vgm_log().unwrap());
+
+        // The state of the APU comes first, with wave RAM before the
+        // registers so that it can be written
+        assert_eq!(writes[0], (0xFF26, 0x80));
+        assert_eq!(writes[1], (0xFF1A, 0x00));
+        assert!(writes[2..18].iter().all(|&(address, _)| address >= 0xFF30));
+        let state = &writes[18..writes.len() - 2];
+        assert_eq!(state.len(), 0xFF26 - 0xFF10);
+        // Frequency, length and envelope are written as the game did
+        assert!(state.contains(&(0xFF17, 0xF0)));
+        assert!(state.contains(&(0xFF12, 0x00)));
+        // Only the notes that are still playing start again
+        assert!(state.contains(&(0xFF14, 0x07)));
+        assert!(state.contains(&(0xFF19, 0x86)));
+
+        // Powering off clears the registers, but only the game writes
+        // are in the log
+        assert_eq!(writes[writes.len() - 2..], [(0xFF24, 0x77), (0xFF26, 0x00)]);
+        assert!(apu.stop_vgm_log().is_none());
+    }
+
+    #[test]
+    fn unused_registers_ignored() {
+        let mut apu = SoundController::new();
+        apu.write(0xFF26, 0x80);
+        apu.start_vgm_log();
+        apu.write(0xFF09, 0x12);
+        apu.write(0xFF24, 0x77);
+        let writes = vgm_writes(&apu.stop_vgm_log().unwrap());
+
+        assert_eq!(writes[writes.len() - 1], (0xFF24, 0x77));
+        assert!(writes.iter().all(|&(address, _)| address >= 0xFF10));
+    }
+}
@@ -0,0 +1,203 @@
+use std::f64::consts::PI;
+
+/// Number of output samples touched by a single step.
+const TAPS: usize = 16;
+/// Sub-sample positions a step can be stamped at.
+const PHASES: usize = 64;
+
+/**
+ * Band-limited step synthesizer, in the style of blip_buf.
+ *
+ * The input is a list of amplitude changes stamped with the clock they
+ * happen at, the output is the same signal resampled at the host rate
+ * without the aliasing that point-sampling a square wave produces.
+ *
+ * Each change adds a windowed-sinc impulse to the buffer and the samples
+ * are integrated when they are read, so that every change turns into a
+ * smooth step.
+ */
+pub struct BlipBuffer {
+    /// Output samples per input clock.
+    factor: f64,
+    /// Position of the current frame start in `buffer`, in samples.
+    offset: f64,
+    buffer: Vec<f32>,
+    /// Samples at the start of `buffer` that are complete.
+    avail: usize,
+    read: usize,
+    integrator: f32,
+    kernel: Vec<[f32; TAPS]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> BlipBuffer {
+        BlipBuffer {
+            factor: sample_rate / clock_rate,
+            offset: 0.0,
+            buffer: vec![],
+            avail: 0,
+            read: 0,
+            integrator: 0.0,
+            kernel: generate_kernel(),
+        }
+    }
+
+    /** Changes the rates without losing the samples in flight, the
+     * current frame should be ended first as its clocks are converted
+     * with the new factor. */
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    /** Adds a change of `delta` in the amplitude at `clock`, relative to
+     * the start of the current frame. */
+    pub fn add_delta(&mut self, clock: u64, delta: f32) {
+        let position = self.offset + clock as f64 * self.factor;
+        let start = position as usize;
+        let phase = ((position - start as f64) * PHASES as f64) as usize;
+
+        if self.buffer.len() < start + TAPS {
+            self.buffer.resize(start + TAPS, 0.0);
+        }
+
+        let kernel = &self.kernel[phase];
+        for (sample, k) in self.buffer[start..start + TAPS].iter_mut().zip(kernel) {
+            *sample += k * delta;
+        }
+    }
+
+    /** Ends the current frame after `clocks` clocks, the samples before
+     * that point can then be read. */
+    pub fn end_frame(&mut self, clocks: u64) {
+        // Forget about the samples already read
+        self.buffer.drain(..self.read);
+        self.offset -= self.read as f64;
+        self.avail -= self.read;
+        self.read = 0;
+
+        self.offset += clocks as f64 * self.factor;
+        self.avail = self.offset as usize;
+
+        if self.buffer.len() < self.avail {
+            self.buffer.resize(self.avail, 0.0);
+        }
+    }
+
+    pub fn read_sample(&mut self) -> Option<f32> {
+        if self.read >= self.avail {
+            return None;
+        }
+
+        self.integrator += self.buffer[self.read];
+        self.buffer[self.read] = 0.0;
+        self.read += 1;
+
+        Some(self.integrator)
+    }
+}
+
+/** Blackman-windowed sinc for each phase. Every phase adds up to 1
+ * so that steps settle on exactly the right amplitude. */
+fn generate_kernel() -> Vec<[f32; TAPS]> {
+    // Keep some room below Nyquist for the transition band
+    let cutoff = 0.9;
+
+    let mut kernel = vec![[0.0; TAPS]; PHASES];
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let shift = phase as f64 / PHASES as f64;
+
+        let mut values = [0.0f64; TAPS];
+        for (i, value) in values.iter_mut().enumerate() {
+            let x = i as f64 - (TAPS / 2) as f64 - shift;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * cutoff * x).sin() / (PI * cutoff * x)
+            };
+
+            let t = (x + (TAPS / 2) as f64) / TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+            *value = sinc * window;
+        }
+
+        let sum: f64 = values.iter().sum();
+        for (tap, value) in taps.iter_mut().zip(values.iter()) {
+            *tap = (value / sum) as f32;
+        }
+    }
+
+    kernel
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(blip: &mut BlipBuffer) -> Vec<f32> {
+        let mut out = vec![];
+        while let Some(sample) = blip.read_sample() {
+            out.push(sample);
+        }
+        out
+    }
+
+    #[test]
+    fn step_settles() {
+        let mut blip = BlipBuffer::new(4194304.0, 44100.0);
+
+        blip.add_delta(1000, 0.5);
+        blip.end_frame(70224);
+
+        let samples = read_all(&mut blip);
+        assert_eq!(samples.len(), 738);
+
+        // Nothing happens before the step
+        assert!(samples[..8].iter().all(|&s| s == 0.0));
+        // And once the step is through the output holds the new amplitude
+        assert!(samples[40..].iter().all(|&s| (s - 0.5).abs() < 0.0001));
+    }
+
+    #[test]
+    fn frames_line_up() {
+        let mut single = BlipBuffer::new(4194304.0, 48000.0);
+        let mut split = BlipBuffer::new(4194304.0, 48000.0);
+
+        for i in 0..100 {
+            let delta = if i % 2 == 0 { 1.0 } else { -1.0 };
+            single.add_delta(i * 997, delta);
+        }
+        single.end_frame(99700);
+
+        let mut split_samples = vec![];
+        for i in 0..100 {
+            let delta = if i % 2 == 0 { 1.0 } else { -1.0 };
+            split.add_delta(0, delta);
+            split.end_frame(997);
+            split_samples.extend(read_all(&mut split));
+        }
+
+        let single_samples = read_all(&mut single);
+        assert_eq!(single_samples.len(), split_samples.len());
+        for (a, b) in single_samples.iter().zip(split_samples.iter()) {
+            assert!((a - b).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn rate_change_keeps_steps() {
+        let mut blip = BlipBuffer::new(4194304.0, 44100.0);
+
+        blip.add_delta(69000, 0.5);
+        blip.end_frame(70224);
+        let mut samples = read_all(&mut blip);
+
+        // The tail of the step is still in the buffer
+        blip.set_rates(4194304.0, 48000.0);
+        blip.end_frame(70224);
+        samples.extend(read_all(&mut blip));
+
+        // The second frame runs at the new rate
+        assert!(samples.len() > 738 + 800);
+        assert!(samples[800..].iter().all(|&s| (s - 0.5).abs() < 0.0001));
+    }
+}
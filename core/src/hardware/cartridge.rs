@@ -1,3 +1,4 @@
+use colorization::{self, Colorization};
 use hardware::cpu::Handler;
 use hardware::memory_controller::MemoryController;
 use std::fmt;
@@ -9,6 +10,9 @@ pub struct Cartridge {
     game_title: String,
     gb_color_game: bool,
     licence_code: u16,
+    old_licence_code: u8,
+    title_checksum: u8,
+    title_fourth_letter: u8,
     super_game_boy: bool,
     memory_controller: MemoryController,
     rom_size: usize,
@@ -78,6 +82,9 @@ impl Cartridge {
                 .unwrap_or("UNKNOWN".to_string()),
             gb_color_game: (copy[0x143] == 0x80),
             licence_code: ((copy[0x144] as u16) << 8) + (copy[0x145] as u16),
+            old_licence_code: copy[0x14B],
+            title_checksum: colorization::title_checksum(&copy[0x134..0x144]),
+            title_fourth_letter: copy[0x137],
             super_game_boy: copy[0x146] == 0x03,
             rom_size: get_rom_size(copy[0x148]),
             ram_size: get_ram_size(copy[0x149]),
@@ -87,6 +94,22 @@ impl Cartridge {
         }
     }
 
+    /// Games are published by Nintendo if the old licensee code is 0x01, or
+    /// if it's 0x33 and the new one is "01".
+    fn nintendo_licensee(&self) -> bool {
+        self.old_licence_code == 0x01
+            || (self.old_licence_code == 0x33 && self.licence_code == 0x3031)
+    }
+
+    /// The palettes the CGB boot ROM would pick for this game.
+    pub fn colorization(&self) -> Colorization {
+        Colorization::for_game(
+            self.title_checksum,
+            self.title_fourth_letter,
+            self.nintendo_licensee(),
+        )
+    }
+
     pub fn ram(&mut self) -> &mut [u8] {
         self.memory_controller.ram()
     }
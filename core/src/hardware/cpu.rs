@@ -1,7 +1,8 @@
-use hardware::apu::AudioBuffer;
+use hardware::apu::{AudioBuffer, Channel, MixOptions};
 use hardware::handler_holder::Key;
 pub use hardware::opcodes::OpCode;
-use hardware::ppu::ScreenBuffer;
+use hardware::ppu::{RawScreenBuffer, RenderOptions, ScreenBuffer};
+use hardware::serial::LinkPort;
 use hardware::timer_controller::TimerController;
 
 use std::cell::RefCell;
@@ -46,6 +47,10 @@ pub struct Cpu {
 
     state: CpuState,
     called_set_PC: bool,
+    /** Set when HALT is executed with IME off and an interrupt already
+     * pending. The CPU doesn't halt and fails to increment PC after
+     * fetching the next opcode, so that byte is read twice. */
+    halt_bug: bool,
 
     cycles: usize,
     interrupt_handler: InterruptHandler,
@@ -81,13 +86,38 @@ pub trait HandlerHolder: MapperHolder {
     fn key_down(&mut self, key: Key);
     fn key_up(&mut self, key: Key);
     fn get_screen_buffer(&self) -> &ScreenBuffer;
+    fn get_raw_screen_buffer(&self) -> &RawScreenBuffer;
     fn get_audio_buffer(&self) -> &dyn AudioBuffer;
+    /// Rate of the samples produced by the APU, in Hz.
+    fn sample_rate(&self) -> f64;
+    fn set_sample_rate(&mut self, sample_rate: f64);
+    /// Small factor applied to the sample rate to keep the host buffer
+    /// level steady.
+    fn set_rate_adjustment(&mut self, factor: f64);
+    /// How many audio samples can be drained right now.
+    fn pending_audio_samples(&mut self) -> usize;
+    /// Moves the oldest audio samples produced into `out`, returns how
+    /// many were copied.
+    fn drain_audio_samples(&mut self, out: &mut [i16]) -> usize;
+    fn drain_channel_samples(&mut self, channel: Channel, out: &mut [i16]) -> usize;
+    fn mix_options(&self) -> MixOptions;
+    /// Logs the writes to the APU registers until `stop_vgm_log`.
+    fn start_vgm_log(&mut self);
+    /// The VGM file logged so far, if a log was started.
+    fn stop_vgm_log(&mut self) -> Option<Vec<u8>>;
+    fn set_mix_options(&mut self, options: MixOptions);
+    /// Plugs the link cable in, `None` unplugs it.
+    fn set_link_port(&mut self, port: Option<Box<dyn LinkPort>>);
     fn cpu_step(&mut self);
     fn check_interrupts(&mut self) -> Option<Interrupt>;
     fn should_refresh(&mut self) -> bool;
     fn ram(&mut self) -> &mut [u8];
     fn rtc(&mut self) -> Option<&mut u64>;
     fn reset(&mut self);
+    /// Allows access to VRAM and OAM even when the PPU is using them.
+    fn set_lenient_access(&mut self, lenient: bool);
+    fn render_options(&self) -> RenderOptions;
+    fn set_render_options(&mut self, options: RenderOptions);
 }
 
 impl Hardware for Cpu {
@@ -136,6 +166,7 @@ impl Cpu {
 
             state: CpuState::Running,
             called_set_PC: false,
+            halt_bug: false,
             cycles: 0,
             interrupt_handler: InterruptHandler::new(),
 
@@ -167,7 +198,9 @@ impl Cpu {
 
         self.state = CpuState::Running;
         self.called_set_PC = false;
+        self.halt_bug = false;
         self.cycles = 0;
+        self.interrupt_handler = InterruptHandler::new();
 
         self.handler_holder.reset();
     }
@@ -241,6 +274,12 @@ impl Cpu {
                 .get_handler_write(address)
                 .write(address, v),
         }
+
+        // Turning the LCD on can request an interrupt in time for the next
+        // instruction boundary
+        while let Some(interrupt) = self.handler_holder.check_interrupts() {
+            self.interrupt_handler.add_interrupt(interrupt);
+        }
     }
 
     #[cfg(feature = "debugger")]
@@ -367,6 +406,21 @@ impl Cpu {
         self.interrupt_handler.enable();
     }
 
+    /// Unlike EI, RETI enables interrupts without any delay.
+    pub fn enable_interrupts_immediately(&mut self) {
+        self.interrupt_handler.enable_immediately();
+    }
+
+    pub fn halt(&mut self) {
+        if !self.interrupt_handler.is_enabled() && self.interrupt_handler.has_interrupts() {
+            // The CPU doesn't halt at all in this case, instead it triggers
+            // the HALT bug on the next opcode fetch.
+            self.halt_bug = true;
+        } else {
+            self.state = CpuState::Halt;
+        }
+    }
+
     pub fn set_BC(&mut self, v: u16) {
         self.B_reg = (v >> 8) as u8;
         self.C_reg = v as u8;
@@ -397,10 +451,6 @@ impl Cpu {
     }
 
     pub fn set_PC(&mut self, v: u16) {
-        if (v > 0x8000 && v < 0xC000) || (v > 0xE000 && v < 0xFF80) {
-            // Likely a bug in the emulator
-            panic!("PC outside of valid range.");
-        }
         self.PC_reg = v;
         self.called_set_PC = true;
     }
@@ -411,9 +461,9 @@ impl Cpu {
         self.interrupt_handler.cpu_step();
         self.handler_holder.cpu_step();
 
-        self.handler_holder
-            .check_interrupts()
-            .map(|i| self.interrupt_handler.add_interrupt(i));
+        while let Some(interrupt) = self.handler_holder.check_interrupts() {
+            self.interrupt_handler.add_interrupt(interrupt);
+        }
     }
 
     pub fn add_cycles(&mut self, mut cycles: usize) {
@@ -430,20 +480,28 @@ impl Cpu {
         self.cycles
     }
 
+    /// Dispatches the highest priority pending interrupt, this takes 5 M-cycles.
     fn interrupt(&mut self) {
+        // M1-M2: the opcode fetched for the next instruction is discarded
+        // and SP is decremented.
         self.add_cycles(8);
 
         let next = self.get_PC();
 
+        // M3: push the high byte of PC. If SP points to $0000 this write
+        // lands on IE and can cancel the interrupt being dispatched.
         let h = (next >> 8) as u8;
         self.push_SP(h);
 
-        // The Gameboy uses the interrupt at this stage rather than
-        // the one 8 cycles ago. Not sure what happens if we _don't_
-        // end up having an interrupt anymore? for now we use the
-        // triggering one
-        let interrupt = self.interrupt_handler.get_interrupt();
+        // The vector is only picked now, after the high byte has been pushed,
+        // so an interrupt requested (or cancelled) during the first three
+        // M-cycles changes which handler gets called.
+        let interrupt = self.interrupt_handler.pending();
+        if let Some(i) = interrupt {
+            self.interrupt_handler.reset(i);
+        }
 
+        // M4: push the low byte of PC.
         let l = ((next << 8) >> 8) as u8;
         self.push_SP(l);
 
@@ -460,10 +518,9 @@ impl Cpu {
             Some(Interrupt::Joypad) => 0x0060,
         };
 
-        interrupt.map(|i| self.interrupt_handler.reset(i));
-
         self.interrupt_handler.disable();
 
+        // M5: jump to the interrupt vector.
         self.set_PC(address);
         self.add_cycles(4);
 
@@ -547,6 +604,13 @@ impl Cpu {
     fn next_opcode(&mut self) -> OpCode {
         let hex = self.deref_PC();
 
+        if self.halt_bug {
+            // PC is not incremented after this fetch, we rewind it by one
+            // so that the byte that follows is read again.
+            self.halt_bug = false;
+            self.PC_reg = self.PC_reg.wrapping_sub(1);
+        }
+
         if hex == 0xCB {
             self.inc_PC();
             OpCode::from_byte(self.deref_PC(), true)
@@ -560,38 +624,46 @@ impl Cpu {
     }
 
     pub fn next_instruction(&mut self) {
-        if self.interrupt_handler.has_interrupts() {
+        if self.state != CpuState::Running {
+            if !self.interrupt_handler.has_interrupts() {
+                self.add_cycles(4);
+                return;
+            }
+
+            // Any interrupt enabled in IE wakes the CPU up, even when IME is
+            // off, in which case we just continue after the HALT.
             self.state = CpuState::Running;
         }
 
-        if self.interrupt_handler.get_interrupt().is_some() {
-            // The actual interrupt will be read later
+        // Interrupts are checked at the boundary between two instructions,
+        // if IME was turned on by EI it will take effect after the next one.
+        let dispatch =
+            self.interrupt_handler.is_enabled() && self.interrupt_handler.has_interrupts();
+        self.interrupt_handler.instruction_boundary();
+
+        if dispatch {
             self.interrupt();
             return;
         }
 
-        if self.state == CpuState::Running {
-            let op = self.next_opcode();
+        let op = self.next_opcode();
 
-            if self.debug {
-                if op.is_prefixed() {
-                    println!("[{:04X}] {}", self.get_PC() - 1, op.to_string());
-                } else {
-                    println!("[{:04X}] {}", self.get_PC(), op.to_string());
-                }
+        if self.debug {
+            if op.is_prefixed() {
+                println!("[{:04X}] {}", self.get_PC() - 1, op.to_string());
+            } else {
+                println!("[{:04X}] {}", self.get_PC(), op.to_string());
             }
+        }
 
-            op.execute(self);
+        op.execute(self);
 
-            if !self.did_call_set_PC() {
-                // No jump happened so we need to increase PC
-                self.inc_PC();
-            } else {
-                self.reset_call_set_PC();
-            }
+        if !self.did_call_set_PC() {
+            // No jump happened so we need to increase PC
+            self.inc_PC();
         } else {
-            self.cpu_step();
-        };
+            self.reset_call_set_PC();
+        }
     }
 }
 
@@ -620,7 +692,8 @@ pub enum Interrupt {
 impl InterruptHandler {
     pub fn new() -> InterruptHandler {
         InterruptHandler {
-            enabled: InterruptStatus::Enabled,
+            // The boot ROM leaves interrupts disabled
+            enabled: InterruptStatus::Disabled,
             register: InterruptRegister::new(),
             timer_controller: TimerController::new(),
         }
@@ -636,16 +709,33 @@ impl InterruptHandler {
         }
     }
 
+    /// EI only sets IME after the instruction following it.
     pub fn enable(&mut self) {
         if self.enabled == InterruptStatus::Disabled {
             self.enabled = InterruptStatus::Enabling;
         }
     }
 
+    pub fn enable_immediately(&mut self) {
+        self.enabled = InterruptStatus::Enabled;
+    }
+
     pub fn disable(&mut self) {
         self.enabled = InterruptStatus::Disabled;
     }
 
+    /// Whether IME is set, a pending EI doesn't count.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled == InterruptStatus::Enabled
+    }
+
+    /// Called once per instruction, after interrupts have been checked.
+    pub fn instruction_boundary(&mut self) {
+        if self.enabled == InterruptStatus::Enabling {
+            self.enabled = InterruptStatus::Enabled;
+        }
+    }
+
     pub fn reset(&mut self, interrupt: Interrupt) {
         self.register.reset(interrupt);
     }
@@ -672,26 +762,14 @@ impl InterruptHandler {
             .map(|i| self.add_interrupt(i));
     }
 
-    /// Checks weather an interrupt is queued up (even if it could be disabled)
+    /// Checks weather an interrupt is requested and enabled in IE, regardless of IME.
     pub fn has_interrupts(&self) -> bool {
-        self.register.v_blank || self.register.stat || self.register.timer || self.register.joypad
+        self.pending().is_some()
     }
 
-    pub fn get_interrupt(&mut self) -> Option<Interrupt> {
-        let is_enabled = match self.enabled {
-            InterruptStatus::Disabled => false,
-            InterruptStatus::Enabling => {
-                // Interrupts take 1 instruction to be enabled
-                self.enabled = InterruptStatus::Enabled;
-                false
-            }
-            InterruptStatus::Enabled => true,
-        };
-
-        if !is_enabled {
-            return None;
-        }
-
+    /// Returns the highest priority interrupt that is both requested in IF
+    /// and enabled in IE, regardless of IME.
+    pub fn pending(&self) -> Option<Interrupt> {
         if self.register.v_blank_enabled && self.register.v_blank {
             return Some(Interrupt::VBlank);
         }
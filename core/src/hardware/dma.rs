@@ -1,9 +1,24 @@
 use hardware::cpu;
 use hardware::cpu::MapperHolder;
 
+/// Writing to 0xFF46 takes one M-cycle to set up the transfer, after that
+/// the OAM and the bus the source is on are blocked and one byte is copied
+/// every M-cycle.
+const SETUP_CYCLES: usize = 4;
+const TRANSFER_CYCLES: usize = 160 * 4;
+
 pub struct DmaController {
     running: bool,
-    base: u16,
+    // While a transfer is in progress the OAM is not accessible from the
+    // CPU, this stays on when a new transfer is started on top of a
+    // running one.
+    blocking: bool,
+    // The PPU is using the OAM
+    ppu_read_blocking: bool,
+    ppu_write_blocking: bool,
+    source: u8,
+    /// The last byte copied, what the CPU reads from the blocked bus.
+    bus: u8,
     cycles: usize,
     pub oam_ram: [u8; 160],
 }
@@ -11,25 +26,19 @@ pub struct DmaController {
 impl cpu::Handler for DmaController {
     fn read(&self, address: u16) -> u8 {
         match address {
+            0xFE00..=0xFE9F if self.blocking || self.ppu_read_blocking => 0xFF,
             0xFE00..=0xFE9F => self.oam_ram[address as usize - 0xFE00],
-            // TODO: not sure what should happen here, so let's just crash
-            0xFF46 => panic!("Trying to read from DMA."),
-            _ => unreachable!(),
+            0xFF46 => self.source,
+            _ => self.bus,
         }
     }
 
     fn write(&mut self, address: u16, v: u8) {
         match address {
+            0xFE00..=0xFE9F if self.blocking || self.ppu_write_blocking => {}
             0xFE00..=0xFE9F => self.oam_ram[address as usize - 0xFE00] = v,
             0xFF46 => {
-                if v >= 0xE0 {
-                    // It's not really clear to me what happens when we try to
-                    // DMA to a high address so let's just crash
-                    unimplemented!();
-                }
-
-                self.base = (v as u16) << 8;
-                // TODO: what if it's already running?
+                self.source = v;
                 self.running = true;
                 self.cycles = 0;
             }
@@ -42,34 +51,102 @@ impl DmaController {
     pub fn new() -> DmaController {
         DmaController {
             running: false,
-            base: 0,
+            blocking: false,
+            ppu_read_blocking: false,
+            ppu_write_blocking: false,
+            source: 0xFF,
+            bus: 0xFF,
             cycles: 0,
             oam_ram: [0; 160],
         }
     }
 
+    pub fn set_ppu_blocking(&mut self, read: bool, write: bool) {
+        self.ppu_read_blocking = read;
+        self.ppu_write_blocking = write;
+    }
+
+    /// While copying, the CPU reads what the DMA reads on the same bus.
+    /// VRAM has its own bus, everything else outside of the CPU shares the
+    /// external one. HRAM and the registers are never blocked.
+    pub fn bus_conflict(&self, address: u16) -> bool {
+        if !self.blocking {
+            return false;
+        }
+
+        let video = |a: u16| (0x8000..=0x9FFF).contains(&a);
+        match address {
+            0xFE00..=0xFFFF => false,
+            _ => video(address) == video((self.source as u16) << 8),
+        }
+    }
+
+    fn source_address(&self, offset: u16) -> u16 {
+        let base = (self.source as u16) << 8;
+        match base {
+            // Past the internal RAM the DMA reads from its echo
+            0xE000..=0xFFFF => base - 0x2000 + offset,
+            _ => base + offset,
+        }
+    }
+
     pub fn cpu_step(&mut self, mapper_holder: &dyn MapperHolder) {
         if !self.running {
             return;
         }
 
-        match self.cycles {
-            0 => {
-                // There's a 1 cycle wait after enabling DMA
-            }
-            1..=160 => {
-                let dma_step = self.cycles as u16 - 1;
-                let from = self.base + dma_step;
-                let v = mapper_holder.get_handler_read(from).read(from);
-                self.oam_ram[dma_step as usize] = v;
-            }
-            161 => {
-                // DMA is done
-                self.running = false;
-            }
-            _ => unreachable!(),
+        self.cycles += cpu::CYCLES_PER_STEP;
+
+        if self.cycles <= SETUP_CYCLES {
+            return;
+        }
+
+        self.blocking = true;
+
+        // The first byte is copied at the end of the M-cycle that follows
+        // the setup, then one every M-cycle
+        let elapsed = self.cycles - SETUP_CYCLES;
+        if elapsed.is_multiple_of(4) && elapsed > 4 {
+            let offset = (elapsed / 4 - 2) as u16;
+            let from = self.source_address(offset);
+            self.bus = mapper_holder.get_handler_read(from).read(from);
+            self.oam_ram[offset as usize] = self.bus;
+        }
+
+        if elapsed == TRANSFER_CYCLES + 4 {
+            self.running = false;
+            self.blocking = false;
         }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use emulator::Emulator;
+
+    #[test]
+    fn blocks_the_source_bus() {
+        let mut emulator = Emulator::from_data(&[0; 0x8000], 44100.0).unwrap();
+        let cpu = &mut emulator.cpu;
+        for i in 0..160 {
+            cpu.set_deref(0xC000 + i, i as u8);
+        }
+        cpu.set_deref(0xC100, 0xAB);
+        cpu.set_deref(0xFF80, 0x77);
+
+        cpu.set_deref(0xFF46, 0xC0);
+        // HRAM is on its own
+        for _ in 0..3 {
+            assert_eq!(cpu.deref(0xFF80), 0x77);
+        }
+        // WRAM shares the bus with the source, the CPU gets the byte
+        // being copied
+        assert_eq!(cpu.deref(0xC100), 0x01);
+        assert_eq!(cpu.deref(0x0000), 0x02);
 
-        self.cycles += 1;
+        for _ in 0..160 {
+            cpu.deref(0xFF80);
+        }
+        assert_eq!(cpu.deref(0xC100), 0xAB);
     }
 }
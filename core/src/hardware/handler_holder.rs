@@ -1,10 +1,9 @@
-use hardware::apu::{AudioBuffer, SoundController};
+use hardware::apu::{AudioBuffer, Channel, MixOptions, SoundController};
 use hardware::cartridge::Cartridge;
 use hardware::cpu;
 use hardware::dma::DmaController;
-use hardware::ppu::{Ppu, ScreenBuffer};
-
-use bitfield::Bitfield;
+use hardware::ppu::{Ppu, RawScreenBuffer, RenderOptions, ScreenBuffer};
+use hardware::serial::{LinkPort, SerialTransfer};
 
 pub struct GBHandlerHolder {
     dma: DmaController,
@@ -42,13 +41,11 @@ impl cpu::MapperHolder for InnerHandlerHolder {
             0x0000..=0x7FFF => &self.cartridge,
             0x8000..=0x9FFF => &self.ppu,
             0xA000..=0xBFFF => &self.cartridge,
-            0xC000..=0xDFFF => &self.memory_holder,
-            // Accessing this in the real GB will return the internal_ram echoed
-            // but it's probably a bug in the emulator, so let's panic
-            0xE000..=0xFDFF => panic!("Tried to access echo of internal ram"),
+            0xC000..=0xFDFF => &self.memory_holder,
             0xFEA0..=0xFEFF => &self.memory_holder,
             0xFF00 => &self.joypad_register,
             0xFF01..=0xFF02 => &self.serial_transfer_controller,
+            0xFF03 | 0xFF08 => &self.memory_holder,
             0xFF09..=0xFF3F => &self.apu,
             0xFF40..=0xFF45 => &self.ppu,
             0xFF47..=0xFF4B => &self.ppu,
@@ -62,11 +59,11 @@ impl cpu::MapperHolder for InnerHandlerHolder {
             0x0000..=0x7FFF => &mut self.cartridge,
             0x8000..=0x9FFF => &mut self.ppu,
             0xA000..=0xBFFF => &mut self.cartridge,
-            0xC000..=0xDFFF => &mut self.memory_holder,
-            0xE000..=0xFDFF => &mut self.memory_holder,
+            0xC000..=0xFDFF => &mut self.memory_holder,
             0xFEA0..=0xFEFF => &mut self.memory_holder,
             0xFF00 => &mut self.joypad_register,
             0xFF01..=0xFF02 => &mut self.serial_transfer_controller,
+            0xFF03 | 0xFF08 => &mut self.memory_holder,
             0xFF09..=0xFF3F => &mut self.apu,
             0xFF40..=0xFF45 => &mut self.ppu,
             0xFF47..=0xFF4B => &mut self.ppu,
@@ -81,6 +78,10 @@ impl InnerHandlerHolder {
         self.ppu.get_screen()
     }
 
+    fn get_raw_screen_buffer(&self) -> &RawScreenBuffer {
+        self.ppu.get_raw_screen()
+    }
+
     fn should_refresh(&mut self) -> bool {
         self.ppu.should_refresh()
     }
@@ -97,13 +98,16 @@ impl InnerHandlerHolder {
         self.joypad_register.key_down(key);
     }
 
-    fn cpu_step(&mut self) {
-        self.ppu.cpu_step();
+    fn cpu_step(&mut self, oam_ram: &[u8]) {
+        self.ppu.cpu_step(oam_ram);
         self.apu.cpu_step();
+        self.serial_transfer_controller.cpu_step();
     }
 
-    fn check_interrupts(&mut self, oam_ram: &[u8]) -> Option<cpu::Interrupt> {
-        self.ppu.check_interrupts(oam_ram)
+    fn check_interrupts(&mut self) -> Option<cpu::Interrupt> {
+        self.ppu
+            .check_interrupts()
+            .or_else(|| self.serial_transfer_controller.check_interrupts())
     }
 
     fn ram(&mut self) -> &mut [u8] {
@@ -114,12 +118,29 @@ impl InnerHandlerHolder {
         self.cartridge.rtc()
     }
 
+    fn set_lenient_access(&mut self, lenient: bool) {
+        self.ppu.set_lenient_access(lenient);
+    }
+
     fn reset(&mut self) {
+        let lenient = self.ppu.lenient_access();
+        let render_options = self.ppu.render_options();
+
         self.memory_holder = MemoryHolder::new();
         self.ppu = Ppu::new();
+        self.ppu.set_lenient_access(lenient);
+        self.ppu.set_render_options(render_options);
         self.joypad_register = JoypadRegister::new();
+        let link_port = self.serial_transfer_controller.take_link_port();
         self.serial_transfer_controller = SerialTransfer::new();
+        self.serial_transfer_controller.set_link_port(link_port);
+        let sample_rate = self.apu.sample_rate();
+        let rate_adjustment = self.apu.rate_adjustment();
+        let mix_options = self.apu.mix_options();
         self.apu = SoundController::new();
+        self.apu.set_sample_rate(sample_rate);
+        self.apu.set_rate_adjustment(rate_adjustment);
+        self.apu.set_mix_options(mix_options);
     }
 }
 
@@ -142,13 +163,15 @@ impl MemoryHolder {
 impl cpu::Handler for MemoryHolder {
     fn read(&self, address: u16) -> u8 {
         match address {
-            0xFEA0..=0xFEFF | 0xFF4C..=0xFF7F => {
+            0xFEA0..=0xFEFF | 0xFF03 | 0xFF08 | 0xFF4C..=0xFF7F => {
                 // This area of the memory is not theoretically accessible but
                 // some games do try to read from here because of bugs in them.
                 // We will just return open bus.
                 0xFF
             }
             0xC000..=0xDFFF => self.internal_ram[(address - 0xC000) as usize],
+            // Echo of the internal RAM
+            0xE000..=0xFDFF => self.internal_ram[(address - 0xE000) as usize],
             0xFF80..=0xFFFE => self.stack[(address - 0xFF80) as usize],
             _ => panic!(format!("Address not supported {:04X}", address)),
         }
@@ -156,11 +179,12 @@ impl cpu::Handler for MemoryHolder {
 
     fn write(&mut self, address: u16, v: u8) {
         match address {
-            0xFEA0..=0xFEFF | 0xFF4C..=0xFF7F => {
+            0xFEA0..=0xFEFF | 0xFF03 | 0xFF08 | 0xFF4C..=0xFF7F => {
                 // This area is not mapped to anything in the game boy hardware,
                 // so writes have no effect.
             }
             0xC000..=0xDFFF => self.internal_ram[(address - 0xC000) as usize] = v,
+            0xE000..=0xFDFF => self.internal_ram[(address - 0xE000) as usize] = v,
             0xFF80..=0xFFFE => self.stack[(address - 0xFF80) as usize] = v,
             _ => panic!(format!("Address not supported {:04X}", address)),
         }
@@ -172,6 +196,7 @@ impl cpu::MapperHolder for GBHandlerHolder {
         match address {
             0xFE00..=0xFE9F => &self.dma,
             0xFF46 => &self.dma,
+            _ if self.dma.bus_conflict(address) => &self.dma,
             _ => self.inner.get_handler_read(address),
         }
     }
@@ -190,6 +215,10 @@ impl cpu::HandlerHolder for GBHandlerHolder {
         self.inner.get_screen_buffer()
     }
 
+    fn get_raw_screen_buffer(&self) -> &RawScreenBuffer {
+        self.inner.get_raw_screen_buffer()
+    }
+
     fn should_refresh(&mut self) -> bool {
         self.inner.should_refresh()
     }
@@ -198,6 +227,50 @@ impl cpu::HandlerHolder for GBHandlerHolder {
         self.inner.get_audio_buffer()
     }
 
+    fn sample_rate(&self) -> f64 {
+        self.inner.apu.sample_rate()
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.inner.apu.set_sample_rate(sample_rate);
+    }
+
+    fn set_rate_adjustment(&mut self, factor: f64) {
+        self.inner.apu.set_rate_adjustment(factor);
+    }
+
+    fn pending_audio_samples(&mut self) -> usize {
+        self.inner.apu.pending_samples()
+    }
+
+    fn drain_audio_samples(&mut self, out: &mut [i16]) -> usize {
+        self.inner.apu.drain_samples(out)
+    }
+
+    fn drain_channel_samples(&mut self, channel: Channel, out: &mut [i16]) -> usize {
+        self.inner.apu.drain_channel_samples(channel, out)
+    }
+
+    fn mix_options(&self) -> MixOptions {
+        self.inner.apu.mix_options()
+    }
+
+    fn start_vgm_log(&mut self) {
+        self.inner.apu.start_vgm_log();
+    }
+
+    fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        self.inner.apu.stop_vgm_log()
+    }
+
+    fn set_mix_options(&mut self, options: MixOptions) {
+        self.inner.apu.set_mix_options(options);
+    }
+
+    fn set_link_port(&mut self, port: Option<Box<dyn LinkPort>>) {
+        self.inner.serial_transfer_controller.set_link_port(port);
+    }
+
     fn key_up(&mut self, key: Key) {
         self.inner.key_up(key);
     }
@@ -207,12 +280,15 @@ impl cpu::HandlerHolder for GBHandlerHolder {
     }
 
     fn cpu_step(&mut self) {
-        self.inner.cpu_step();
+        self.inner.cpu_step(&self.dma.oam_ram);
         self.dma.cpu_step(&mut self.inner);
+        let ppu = &self.inner.ppu;
+        self.dma
+            .set_ppu_blocking(ppu.oam_read_blocked(), ppu.oam_write_blocked());
     }
 
     fn check_interrupts(&mut self) -> Option<cpu::Interrupt> {
-        self.inner.check_interrupts(&self.dma.oam_ram)
+        self.inner.check_interrupts()
     }
 
     fn ram(&mut self) -> &mut [u8] {
@@ -223,56 +299,21 @@ impl cpu::HandlerHolder for GBHandlerHolder {
         self.inner.rtc()
     }
 
-    fn reset(&mut self) {
-        self.inner.reset();
-        self.dma = DmaController::new();
+    fn set_lenient_access(&mut self, lenient: bool) {
+        self.inner.set_lenient_access(lenient);
     }
-}
 
-memory_mapper! {
-    name: SerialTransferController,
-    fields: [
-        0xFF01, 0b00000000, transfer_data, 0;
-    ],
-    bitfields: {
-        getters: [
-            0xFF02, 0b01111110, flags, 0, [
-                get_0, shift_clock, u8;
-                get_1, fast_clock, u8;
-                get_7, start_transfer, u8
-            ]
-        ],
-        getter_setters: [
-        ],
-    },
-}
-
-struct SerialTransfer {
-    debug_data: Vec<u8>,
-    mapper: SerialTransferController,
-}
-
-impl SerialTransfer {
-    pub fn new() -> SerialTransfer {
-        SerialTransfer {
-            debug_data: vec![],
-            mapper: SerialTransferController::new(),
-        }
+    fn render_options(&self) -> RenderOptions {
+        self.inner.ppu.render_options()
     }
-}
 
-impl cpu::Handler for SerialTransfer {
-    fn read(&self, address: u16) -> u8 {
-        self.mapper.read(address)
+    fn set_render_options(&mut self, options: RenderOptions) {
+        self.inner.ppu.set_render_options(options);
     }
 
-    fn write(&mut self, address: u16, v: u8) {
-        if address == 0xFF01 {
-            print!("{}", v as char);
-            // Blargg's test roms use this address to print debug information
-            self.debug_data.push(v);
-        }
-        self.mapper.write(address, v);
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.dma = DmaController::new();
     }
 }
 
@@ -112,37 +112,21 @@ macro_rules! memory_mapper {
     }
 }
 
-macro_rules! memory_handler {
-    {
-        parent: $parent: ident,
-        mapper: $mapper: ident,
-        callback: $callback: ident,
-    } => {
-        impl cpu::Handler for $parent {
-            fn read(&self, address: u16) -> u8 {
-                self.$mapper.read(address)
-            }
-
-            fn write(&mut self, address: u16, v: u8) {
-                self.$mapper.write(address, v);
-                self.$callback(address);
-            }
-        }
-    }
-}
-
 #[allow(non_snake_case)]
 pub mod cpu;
 #[allow(non_snake_case)]
 pub mod opcodes;
 
 pub mod apu;
+pub mod blip;
 pub mod dma;
 #[allow(non_snake_case)]
 #[allow(non_camel_case_types)]
 pub mod memory_controller;
 pub mod ppu;
+pub mod serial;
 pub mod timer_controller;
+pub mod vgm;
 
 pub mod cartridge;
 
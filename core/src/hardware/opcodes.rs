@@ -1259,13 +1259,6 @@ fn add_HL_SP(cpu: &mut Cpu) {
     op_HL_SP(add_16, cpu);
 }
 
-fn op_SP_x(func: fn(x: u16, y: u8, cpu: &mut Cpu) -> u16, cpu: &mut Cpu) {
-    let x = cpu.get_SP();
-    let y = next_value(cpu);
-    let result = func(x, y, cpu);
-    cpu.set_SP(result);
-}
-
 fn add_16_8(x: u16, y: u8, cpu: &mut Cpu) -> u16 {
     // The C and H Flags are based on the unsigned value of n,
     // rather than the signed value and the lower byte
@@ -1290,9 +1283,13 @@ fn add_16_8(x: u16, y: u8, cpu: &mut Cpu) -> u16 {
 }
 
 fn add_SP_x(cpu: &mut Cpu) {
+    let sp = cpu.get_SP();
+    let n = next_value(cpu);
+
     // Internal delay
     cpu.add_cycles(8);
-    op_SP_x(add_16_8, cpu);
+    let result = add_16_8(sp, n, cpu);
+    cpu.set_SP(result);
 }
 
 fn op_HL(func: fn(v: u16, cpu: &mut Cpu) -> u16, cpu: &mut Cpu) {
@@ -1458,7 +1455,7 @@ fn scf(cpu: &mut Cpu) {
 }
 
 fn halt(cpu: &mut Cpu) {
-    cpu.set_state(CpuState::Halt);
+    cpu.halt();
 }
 
 fn stop(cpu: &mut Cpu) {
@@ -1682,15 +1679,15 @@ fn call_nn(cpu: &mut Cpu) {
     cpu.inc_PC();
     let next = cpu.get_PC();
 
+    // Internal delay
+    cpu.add_cycles(4);
+
     let h = (next >> 8) as u8;
     cpu.push_SP(h);
 
     let l = ((next << 8) >> 8) as u8;
     cpu.push_SP(l);
 
-    // Internal delay
-    cpu.add_cycles(4);
-
     cpu.set_PC(nn);
 }
 
@@ -1723,13 +1720,15 @@ fn rst_n(n: u8, cpu: &mut Cpu) {
     cpu.inc_PC();
     let next = cpu.get_PC();
 
+    // Internal delay
+    cpu.add_cycles(4);
+
     let h = (next >> 8) as u8;
     cpu.push_SP(h);
 
     let l = ((next << 8) >> 8) as u8;
     cpu.push_SP(l);
 
-    cpu.add_cycles(4);
     cpu.set_PC(n as u16);
 }
 
@@ -1793,7 +1792,7 @@ fn ret_C(cpu: &mut Cpu) {
 
 fn reti(cpu: &mut Cpu) {
     ret(cpu);
-    ei(cpu);
+    cpu.enable_interrupts_immediately();
 }
 
 fn swap(x: u8, cpu: &mut Cpu) -> u8 {
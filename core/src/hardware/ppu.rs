@@ -1,5 +1,6 @@
 use bitfield::Bitfield;
 use hardware::cpu;
+use std::cmp;
 
 /* Represents a shade of gray */
 u8_enum! {
@@ -20,25 +21,169 @@ const BACKGROUND_Y: usize = 256;
 
 const SCANLINE_CYCLES: usize = 456;
 
+// Dot of the scanline at which LY changes, the one at which OAM search
+// starts, the one at which the LY=LYC comparator sees the new LY, and the
+// one at which the pixel transfer starts.
+const LY_UPDATE: usize = 2;
+const OAM_SEARCH_START: usize = 4;
+const LY_COMPARE: usize = 5;
+const LCD_TRANSFER_START: usize = 84;
+
+// Turning the LCD on starts the first line a few dots in, past the start
+// of OAM search.
+const LCD_ON_DOT: usize = 8;
+
+// Length of mode 3 when there is no fine scroll, window or sprites.
+const LCD_TRANSFER_CYCLES: usize = 172;
+
+// The window fetch costs 6 extra cycles.
+const WINDOW_PENALTY: usize = 6;
+// Every sprite costs 6 cycles plus a wait for the background fetcher of up
+// to 5 cycles.
+const SPRITE_PENALTY: usize = 6;
+const MAX_SPRITE_FETCHER_WAIT: usize = 5;
+
+const LAST_LINE: usize = 153;
+
+// The mode reported in STAT lags behind the one the STAT interrupt sees.
+const STAT_MODE_DELAY: usize = 2;
+
 // Includes invisible lines
 const VERTICAL_LINES: usize = 154;
 
 const SCREEN_CYCLES: usize = SCANLINE_CYCLES * VERTICAL_LINES;
 
+/// The layers the PPU draws the screen from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
+/// Debugging switches for what ends up on screen, they don't affect the
+/// timing of the PPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    pub background: bool,
+    pub window: bool,
+    pub sprites: bool,
+    /// Outlines the sprites on screen
+    pub sprite_boxes: bool,
+}
+
+impl RenderOptions {
+    pub fn new() -> RenderOptions {
+        RenderOptions {
+            background: true,
+            window: true,
+            sprites: true,
+            sprite_boxes: false,
+        }
+    }
+
+    pub fn visible(&self, layer: Layer) -> bool {
+        match layer {
+            Layer::Background => self.background,
+            Layer::Window => self.window,
+            Layer::Sprites => self.sprites,
+        }
+    }
+
+    pub fn set_visible(&mut self, layer: Layer, visible: bool) {
+        match layer {
+            Layer::Background => self.background = visible,
+            Layer::Window => self.window = visible,
+            Layer::Sprites => self.sprites = visible,
+        }
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions::new()
+    }
+}
+
 pub type ScreenBuffer = [[GrayShade; SCREEN_X]; SCREEN_Y];
 
+/// Where the pixel on screen comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelSource {
+    Background,
+    Window,
+    /// Sprite using the OBP0 palette
+    Sprite0,
+    /// Sprite using the OBP1 palette
+    Sprite1,
+}
+
+/// A pixel before the palette is applied, this lets front-ends pick
+/// different colors for each layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawPixel {
+    /// The 2-bit color index from the tile data
+    pub color: u8,
+    pub source: PixelSource,
+    /// Value of the palette register (BGP, OBP0 or OBP1) when the pixel
+    /// was drawn, `(palette >> (color * 2)) & 0b11` is the shade of gray.
+    pub palette: u8,
+}
+
+impl RawPixel {
+    pub fn blank() -> RawPixel {
+        RawPixel {
+            color: 0,
+            source: PixelSource::Background,
+            palette: 0,
+        }
+    }
+}
+
+pub type RawScreenBuffer = [[RawPixel; SCREEN_X]; SCREEN_Y];
+
 pub struct Ppu {
     cycles: usize,
     video_ram: [u8; 8196],
     screen_buffer: ScreenBuffer,
+    raw_screen_buffer: RawScreenBuffer,
     should_refresh: bool,
     mapper: VideoMemoryMapper,
     mode: LCDMode,
+    /// LY as seen by the LY=LYC comparator, for a few cycles after LY
+    /// changes the comparator doesn't match any value.
+    ly_compare: Option<u8>,
+    /// All the STAT interrupt sources are OR'ed together, the interrupt is
+    /// only requested when this signal goes from low to high.
+    stat_signal: bool,
+    /// On the first line after the LCD is turned on OAM and VRAM are only
+    /// locked once STAT shows mode 3.
+    lcd_on_line: bool,
+    lcd_transfer_end: usize,
+    stat_mode_delay: usize,
+    vblank_interrupt: bool,
+    stat_interrupt: bool,
     visible_sprites: [usize; 10],
     visible_sprites_len: usize,
-    background: [u8; SCREEN_X],
+    /// How long the pixel output stops for each of the visible sprites
+    sprite_stalls: [usize; 10],
+    sprite_stall: usize,
+    /// The first of the visible sprites that hasn't been fetched yet
+    next_sprite: usize,
     pixel_fifo: PixelPipeline,
     x: usize,
+    /// Set once LY matched WY in the current frame, the window can't show
+    /// up before that even if WY is changed later.
+    window_y_triggered: bool,
+    /// The row of the window that's drawn next. It only increments on
+    /// lines where the window was actually drawn, so hiding the window for
+    /// a few lines doesn't skip any of its rows.
+    window_line: usize,
+    window_drawn: bool,
+    /// Lets the CPU access VRAM and OAM in any mode, for homebrew that
+    /// doesn't respect the timings.
+    lenient_access: bool,
+    render_options: RenderOptions,
 }
 
 u8_enum! {
@@ -50,24 +195,6 @@ u8_enum! {
     }
 }
 
-#[inline]
-fn pattern_value(video_ram: &[u8], offset: usize, x: usize, y: usize) -> u8 {
-    let l = video_ram[offset + (y * 2)];
-    let h = video_ram[offset + (y * 2) + 1];
-
-    match x {
-        7 => (0b00000001 & l) + ((0b00000001 & h) << 1),
-        6 => ((0b00000010 & l) >> 1) + (0b00000010 & h),
-        5 => ((0b00000100 & l) >> 2) + ((0b00000100 & h) >> 1),
-        4 => ((0b00001000 & l) >> 3) + ((0b00001000 & h) >> 2),
-        3 => ((0b00010000 & l) >> 4) + ((0b00010000 & h) >> 3),
-        2 => ((0b00100000 & l) >> 5) + ((0b00100000 & h) >> 4),
-        1 => ((0b01000000 & l) >> 6) + ((0b01000000 & h) >> 5),
-        0 => ((0b10000000 & l) >> 7) + ((0b10000000 & h) >> 6),
-        _ => unreachable!(),
-    }
-}
-
 impl cpu::Handler for Ppu {
     fn read(&self, address: u16) -> u8 {
         match address {
@@ -77,13 +204,19 @@ impl cpu::Handler for Ppu {
     }
 
     fn write(&mut self, address: u16, v: u8) {
+        let lcd_on = self.mapper.lcd_on();
+
         match address {
             0x8000..=0x9FFF => self.write_ram(address, v),
             0xFF41 => self.write_stat(v),
+            // LY is read only
+            0xFF44 => {}
             _ => self.mapper.write(address, v),
         }
 
-        self.write_callback(address);
+        if lcd_on != self.mapper.lcd_on() {
+            self.lcd_toggled();
+        }
     }
 }
 
@@ -101,20 +234,35 @@ impl Ppu {
             cycles: 0,
             video_ram: [0; 8196],
             screen_buffer: [[GrayShade::C00; SCREEN_X]; SCREEN_Y],
+            raw_screen_buffer: [[RawPixel::blank(); SCREEN_X]; SCREEN_Y],
             should_refresh: false,
             mapper: VideoMemoryMapper::new(),
             mode: LCDMode::HBlank,
+            ly_compare: None,
+            stat_signal: false,
+            lcd_on_line: false,
+            lcd_transfer_end: 0,
+            stat_mode_delay: 0,
+            vblank_interrupt: false,
+            stat_interrupt: false,
             visible_sprites: [0; 10],
             visible_sprites_len: 0,
-            background: [0; SCREEN_X],
+            sprite_stalls: [0; 10],
+            sprite_stall: 0,
+            next_sprite: 0,
             pixel_fifo: PixelPipeline::new(),
             x: 0,
+            window_y_triggered: false,
+            window_line: 0,
+            window_drawn: false,
+            lenient_access: false,
+            render_options: RenderOptions::new(),
         }
     }
 
     fn set_mode(&mut self, mode: LCDMode) {
         self.mode = mode;
-        self.mapper.set_mode(mode);
+        self.stat_mode_delay = STAT_MODE_DELAY;
     }
 
     fn write_stat(&mut self, v: u8) {
@@ -122,16 +270,23 @@ impl Ppu {
         self.mapper.stat.set_3456((v >> 3) & 0b1111);
     }
 
-    fn write_callback(&mut self, address: u16) {
-        match address {
-            0xFF40 => {
-                if self.mapper.lcd_on() == 0 {
-                    self.cycles = 0;
-                    self.set_mode(LCDMode::HBlank);
-                    self.mapper.lcd_y_coordinate = 0;
-                }
-            }
-            _ => {}
+    fn lcd_toggled(&mut self) {
+        self.cycles = 0;
+        self.set_mode(LCDMode::HBlank);
+        self.mapper.set_mode(LCDMode::HBlank);
+        self.stat_mode_delay = 0;
+        self.mapper.lcd_y_coordinate = 0;
+        self.reset_window();
+
+        if self.mapper.lcd_on() == 1 {
+            self.cycles = LCD_ON_DOT;
+            self.ly_compare = Some(0);
+            self.lcd_on_line = true;
+            self.window_y_triggered = self.mapper.window_y == 0;
+            // LY = LYC can request an interrupt right away
+            self.update_stat_signal();
+        } else {
+            self.ly_compare = None;
         }
     }
 
@@ -149,6 +304,10 @@ impl Ppu {
         &self.screen_buffer
     }
 
+    pub fn get_raw_screen(&self) -> &RawScreenBuffer {
+        &self.raw_screen_buffer
+    }
+
     pub fn should_refresh(&mut self) -> bool {
         let result = self.should_refresh;
         self.should_refresh = false;
@@ -157,20 +316,36 @@ impl Ppu {
 
     /// Checks if the current x is the start of the window section
     fn check_window_x(&mut self) -> bool {
+        let window_x = self.mapper.window_x as usize;
+
+        // With WX < 7 the window starts at the first pixel, with its
+        // leftmost pixels cut off.
         if self.mapper.window_on() == 1
             && self.mapper.bg_window_on() == 1
-            && self.x + 7 == self.mapper.window_x as usize
-            && self.scanline() >= self.mapper.window_y
+            && self.window_y_triggered
+            && !self.window_drawn
+            && self.x < SCREEN_X
+            && self.x + 7 == cmp::max(window_x, 7)
         {
-            let y = self.scanline() as usize - self.mapper.window_y as usize;
-            self.pixel_fifo
-                .reset(0, BackgroundFetcher::new(self.window_offset(), 0, y));
+            let drop = 7 - cmp::min(window_x, 7);
+            self.pixel_fifo.reset(
+                drop,
+                BackgroundFetcher::new(self.window_offset(), 0, self.window_line),
+            );
+            self.lcd_transfer_end += WINDOW_PENALTY;
+            self.window_drawn = true;
             return true;
         }
 
         false
     }
 
+    fn reset_window(&mut self) {
+        self.window_y_triggered = false;
+        self.window_line = 0;
+        self.window_drawn = false;
+    }
+
     fn scanline_offset(&self, scroll_y: i16) -> usize {
         let offset = ((self.scanline() as i16 + scroll_y) / 8) as usize % (BACKGROUND_Y / 8);
         offset * 32
@@ -183,7 +358,7 @@ impl Ppu {
             0x1C00
         };
 
-        offset + self.scanline_offset(-(self.mapper.window_y as i16))
+        offset + (self.window_line / 8) * 32
     }
 
     fn background_offset(&self) -> usize {
@@ -196,16 +371,67 @@ impl Ppu {
         offset + self.scanline_offset(self.mapper.scroll_bg_y as i16)
     }
 
-    fn write_raw_pixel(&mut self, x: usize, y: usize, color: GrayShade) {
+    fn write_raw_pixel(&mut self, x: usize, y: usize, color: GrayShade, pixel: RawPixel) {
         if x >= SCREEN_X || y >= SCREEN_Y {
             return;
         }
 
         self.screen_buffer[y][x] = color;
+        self.raw_screen_buffer[y][x] = pixel;
+    }
+
+    pub fn set_lenient_access(&mut self, lenient: bool) {
+        self.lenient_access = lenient;
+    }
+
+    pub fn lenient_access(&self) -> bool {
+        self.lenient_access
+    }
+
+    pub fn set_render_options(&mut self, options: RenderOptions) {
+        self.render_options = options;
+    }
+
+    pub fn render_options(&self) -> RenderOptions {
+        self.render_options
+    }
+
+    /// Whether the PPU is in `mode`, either as it sees it or as STAT
+    /// shows it. STAT lags behind the PPU, reads are blocked as soon as the
+    /// PPU switches but writes only once STAT does.
+    fn read_locked(&self, mode: LCDMode) -> bool {
+        self.mapper.mode() == mode || (self.mode == mode && !self.lcd_on_line)
+    }
+
+    /// The PPU reads VRAM during the pixel transfer, the CPU can't access
+    /// it at the same time.
+    fn vram_read_blocked(&self) -> bool {
+        !self.lenient_access && self.read_locked(LCDMode::LCDTransfer)
+    }
+
+    fn vram_write_blocked(&self) -> bool {
+        !self.lenient_access && self.mapper.mode() == LCDMode::LCDTransfer
+    }
+
+    /// OAM is in use during both OAM search and the pixel transfer.
+    pub fn oam_read_blocked(&self) -> bool {
+        !self.lenient_access
+            && (self.read_locked(LCDMode::SearchingOAM) || self.read_locked(LCDMode::LCDTransfer))
+    }
+
+    /// Writes go through for a moment between OAM search and the pixel
+    /// transfer, while STAT still shows mode 2.
+    pub fn oam_write_blocked(&self) -> bool {
+        !self.lenient_access
+            && match self.mapper.mode() {
+                LCDMode::SearchingOAM => self.mode != LCDMode::LCDTransfer,
+                LCDMode::LCDTransfer => true,
+                _ => false,
+            }
     }
 
     pub fn read_ram(&self, address: u16) -> u8 {
-        if self.mode == LCDMode::LCDTransfer {
+        if self.vram_read_blocked() {
             0xFF
         } else {
             self.video_ram[(address - 0x8000) as usize]
@@ -213,35 +439,39 @@ impl Ppu {
     }
 
     pub fn write_ram(&mut self, address: u16, v: u8) {
-        if self.mode == LCDMode::LCDTransfer {
-            // In theory the gb should not be allowed to write to RAM
-            // when in this state TODO: double check. In practice I suspect
-            // there are some timing bugs that make this miss some video ram
-            // updates. For now we ignore this and just happily write to RAM.
+        if self.vram_write_blocked() {
+            return;
         }
 
         self.video_ram[(address - 0x8000) as usize] = v;
     }
 
-    pub fn cpu_step(&mut self) {
+    pub fn cpu_step(&mut self, oam_ram: &[u8]) {
         if self.mapper.lcd_on() == 0 {
             return;
         }
 
-        self.cycles = (self.cycles + cpu::CYCLES_PER_STEP) % SCREEN_CYCLES;
-    }
+        for _ in 0..cpu::CYCLES_PER_STEP {
+            self.cycles = (self.cycles + 1) % SCREEN_CYCLES;
+            self.dot(oam_ram);
+            self.update_stat_signal();
 
-    fn switch_to(&mut self, mode: LCDMode) -> Option<cpu::Interrupt> {
-        let enabled = match mode {
-            LCDMode::SearchingOAM => self.mapper.oam_interrupt() == 1,
-            LCDMode::HBlank => self.mapper.h_blank_interrupt() == 1,
-            LCDMode::VBlank => self.mapper.v_blank_interrupt() == 1,
-            LCDMode::LCDTransfer => false,
-        };
-
-        self.set_mode(mode);
+            if self.stat_mode_delay > 0 {
+                self.stat_mode_delay -= 1;
+                if self.stat_mode_delay == 0 {
+                    self.mapper.set_mode(self.mode);
+                }
+            }
+        }
+    }
 
-        if enabled {
+    /// Returns the interrupts requested since the last call, one at a time.
+    pub fn check_interrupts(&mut self) -> Option<cpu::Interrupt> {
+        if self.vblank_interrupt {
+            self.vblank_interrupt = false;
+            Some(cpu::Interrupt::VBlank)
+        } else if self.stat_interrupt {
+            self.stat_interrupt = false;
             Some(cpu::Interrupt::Stat)
         } else {
             None
@@ -252,193 +482,351 @@ impl Ppu {
         self.mapper.lcd_y_coordinate
     }
 
-    fn update_scanline(&mut self) {
-        assert!(
-            self.scanline() == 153 || self.scanline() + 1 == (self.cycles / SCANLINE_CYCLES) as u8
-        );
+    fn set_scanline(&mut self, scanline: u8) {
+        self.mapper.lcd_y_coordinate = scanline;
+        // It takes a few cycles for the comparator to see the new value
+        self.ly_compare = None;
+    }
 
-        // Beginning of the scanline, we need to update LY
-        self.mapper.lcd_y_coordinate = (self.cycles / SCANLINE_CYCLES) as u8;
+    fn update_stat_signal(&mut self) {
+        let coincidence = self.ly_compare == Some(self.mapper.lyc_coincidence);
+        self.mapper.set_ly_coincidence(coincidence as u8);
 
-        // In this cycle, ly_coincidence is 0 no matter what
-        self.mapper.set_ly_coincidence(0);
-    }
+        // The OAM interrupt also fires at the beginning of VBlank
+        let oam = self.mode == LCDMode::SearchingOAM
+            || self.cycles == SCREEN_Y * SCANLINE_CYCLES + OAM_SEARCH_START;
 
-    fn update_ly_lyc_coincidence(&mut self) -> Option<cpu::Interrupt> {
-        if self.scanline() == self.mapper.lyc_coincidence {
-            self.mapper.set_ly_coincidence(1);
-            if self.mapper.lyc_ly_coincidence_interrupt() == 1 {
-                return Some(cpu::Interrupt::Stat);
-            }
+        let signal = (coincidence && self.mapper.lyc_ly_coincidence_interrupt() == 1)
+            || (self.mode == LCDMode::HBlank && self.mapper.h_blank_interrupt() == 1)
+            || (self.mode == LCDMode::VBlank && self.mapper.v_blank_interrupt() == 1)
+            || (oam && self.mapper.oam_interrupt() == 1);
+
+        if signal && !self.stat_signal {
+            self.stat_interrupt = true;
         }
 
-        None
+        self.stat_signal = signal;
     }
 
-    fn check_interrupts_vblank(&mut self) -> Option<cpu::Interrupt> {
-        match self.cycles % SCANLINE_CYCLES {
-            0 => self.update_scanline(),
-            4 => {
-                let mut interrupt = self.update_ly_lyc_coincidence();
+    fn dot(&mut self, oam_ram: &[u8]) {
+        let line = self.cycles / SCANLINE_CYCLES;
+        let dot = self.cycles % SCANLINE_CYCLES;
 
-                if self.scanline() == 0 {
-                    // End of VBlank
-                    interrupt = interrupt.or(self.switch_to(LCDMode::SearchingOAM));
-                }
+        if line >= SCREEN_Y {
+            self.vblank_dot(line, dot);
+            return;
+        }
 
-                return interrupt;
+        match dot {
+            // Line 0 follows line 153, which already shows LY = 0 and
+            // compared it to LYC
+            LY_UPDATE if line == 0 => {}
+            LY_UPDATE => {
+                self.set_scanline(line as u8);
+                self.lcd_on_line = false;
             }
-            8..=452 => {
-                // VBlank running, nothing to do
+            OAM_SEARCH_START => {
+                if self.scanline() == self.mapper.window_y {
+                    self.window_y_triggered = true;
+                }
+                self.set_mode(LCDMode::SearchingOAM);
             }
-            _ => unreachable!(),
+            LY_COMPARE => self.ly_compare = Some(self.scanline()),
+            LCD_TRANSFER_START => self.start_lcd_transfer(oam_ram),
+            _ if dot > LCD_TRANSFER_START && dot < self.lcd_transfer_end => {
+                self.render_dot(oam_ram)
+            }
+            _ if dot == self.lcd_transfer_end => self.end_lcd_transfer(oam_ram),
+            _ => {}
         }
-
-        None
     }
 
-    pub fn check_interrupts(&mut self, oam_ram: &[u8]) -> Option<cpu::Interrupt> {
-        if self.mapper.lcd_on() == 0 {
-            return None;
+    fn vblank_dot(&mut self, line: usize, dot: usize) {
+        match (line, dot) {
+            (_, 0) => self.set_scanline(line as u8),
+            (SCREEN_Y, OAM_SEARCH_START) => {
+                self.ly_compare = Some(self.scanline());
+                self.set_mode(LCDMode::VBlank);
+                self.vblank_interrupt = true;
+                self.reset_window();
+
+                // Let's notify the front-end that we're ready to refresh the screen
+                self.should_refresh = true;
+            }
+            (_, OAM_SEARCH_START) => self.ly_compare = Some(self.scanline()),
+            // LY goes back to 0 at the very beginning of the last line
+            (LAST_LINE, 8) => self.set_scanline(0),
+            (LAST_LINE, 12) => self.ly_compare = Some(0),
+            _ => {}
         }
+    }
 
-        let mut interrupt = None;
+    fn start_lcd_transfer(&mut self, oam_ram: &[u8]) {
+        self.set_mode(LCDMode::LCDTransfer);
 
-        if self.cycles % 4 != 0 {
-            // We only care about M-cycles
-            return None;
+        let scanline = self.scanline() as usize;
+        let (sprites, len) = SpriteModule {
+            oam_ram,
+            mapper: &self.mapper,
+        }
+        .visible_sprites(scanline);
+        self.visible_sprites = sprites;
+        self.visible_sprites_len = len;
+        self.next_sprite = 0;
+        self.sprite_stall = 0;
+        self.pixel_fifo.reset_sprites();
+
+        let scroll = self.mapper.scroll_bg_x as usize % 8;
+        self.lcd_transfer_end =
+            LCD_TRANSFER_START + LCD_TRANSFER_CYCLES + scroll + self.sprite_penalty(oam_ram);
+
+        self.x = 0;
+        if self.mapper.bg_window_on() == 1 {
+            let y = (self.scanline() as usize + self.mapper.scroll_bg_y as usize) % BACKGROUND_Y;
+            self.pixel_fifo.reset(
+                scroll,
+                BackgroundFetcher::new(
+                    self.background_offset(),
+                    self.mapper.scroll_bg_x as usize / 8,
+                    y,
+                ),
+            );
         }
+        self.check_window_x();
+    }
 
-        if self.mode == LCDMode::VBlank {
-            return self.check_interrupts_vblank();
+    /// The extra cycles that fetching the sprites on this line adds to
+    /// mode 3. Only the leftmost pixel of every sprite matters: the
+    /// background fetcher has to finish the tile that pixel is in, unless
+    /// another sprite already waited for the same tile.
+    fn sprite_penalty(&mut self, oam_ram: &[u8]) -> usize {
+        self.sprite_stalls = [0; 10];
+
+        if self.mapper.obj_sprite_display() == 0 {
+            return 0;
         }
 
-        // If we're not in VBlank we must have a coordinate inside the screen
-        assert!(self.scanline() as usize <= SCREEN_Y);
+        let scroll = self.mapper.scroll_bg_x as usize % 8;
+        let mut penalty = 0;
+        let mut last_tile = None;
+
+        for i in 0..self.visible_sprites_len {
+            let x = oam_ram[self.visible_sprites[i] * 4 + 1] as usize;
+            if x >= SCREEN_X + 8 {
+                // Sprites past the right edge are never fetched
+                continue;
+            }
 
-        let scanline_cycle = self.cycles % SCANLINE_CYCLES;
+            let mut stall = SPRITE_PENALTY;
 
-        match scanline_cycle {
-            0 => self.update_scanline(),
-            4 => {
-                interrupt = interrupt.or(self.update_ly_lyc_coincidence());
+            let tile = (x + scroll) / 8;
+            if last_tile != Some(tile) {
+                let remaining = 7 - (x + scroll) % 8;
+                stall += remaining.saturating_sub(2).min(MAX_SPRITE_FETCHER_WAIT);
+                last_tile = Some(tile);
+            }
 
-                if self.scanline() == SCREEN_Y as u8 {
-                    // Let's notify the front-end that we're ready to refresh the screen
-                    self.should_refresh = true;
+            self.sprite_stalls[i] = stall;
+            penalty += stall;
+        }
 
-                    // VBlank interrupt takes precedence over STAT
-                    let _ = self.switch_to(LCDMode::VBlank);
-                    return Some(cpu::Interrupt::VBlank);
-                }
+        penalty
+    }
 
-                interrupt = interrupt.or(self.switch_to(LCDMode::SearchingOAM));
+    /// Fetches the sprites that start at the current x into the sprite
+    /// FIFO, returns true if the pixel output has to wait for them.
+    fn fetch_sprites(&mut self, oam_ram: &[u8]) -> bool {
+        while self.next_sprite < self.visible_sprites_len {
+            let id = self.visible_sprites[self.next_sprite];
+            let sprite_x = oam_ram[id * 4 + 1] as usize;
+            if sprite_x > self.x + 8 {
+                break;
             }
-            8..=76 => {
-                // The ppu is running OAM Search, do nothing
+
+            let stall = self.sprite_stalls[self.next_sprite];
+            self.next_sprite += 1;
+
+            if self.mapper.obj_sprite_display() == 0 {
+                continue;
             }
-            80 => {
-                let scanline = self.scanline() as usize;
+
+            let fetcher = {
                 let sprite_module = SpriteModule {
                     oam_ram,
-                    video_ram: &self.video_ram,
                     mapper: &self.mapper,
-                    background: &self.background,
-                    screen_buffer: &mut self.screen_buffer,
                 };
+                SpriteFetcher::new(
+                    id,
+                    self.scanline() as usize + 16 - sprite_module.sprite_y(id),
+                    sprite_module.sprite_flags(id),
+                )
+            };
 
-                let (sprites, len) = sprite_module.visible_sprites(scanline);
-                self.visible_sprites = sprites;
-                self.visible_sprites_len = len;
-            }
-            84 => {
-                interrupt = interrupt.or(self.switch_to(LCDMode::LCDTransfer));
-                self.x = 0;
-                if self.mapper.bg_window_on() == 1 {
-                    let y = (self.scanline() + self.mapper.scroll_bg_y) as usize;
-                    self.pixel_fifo.reset(
-                        (self.mapper.scroll_bg_x % 8) as usize,
-                        BackgroundFetcher::new(
-                            self.background_offset(),
-                            self.mapper.scroll_bg_x as usize / 8,
-                            y,
-                        ),
-                    );
-                    // FIXME: every CPU cycle is 2 PPU cycles
-                    for _ in 0..2 {
-                        self.pixel_fifo.step(&self.mapper, &self.video_ram, oam_ram);
-                    }
-                }
-                self.check_window_x();
+            // Sprites partially hidden by the left edge lose their first pixels
+            let drop = self.x + 8 - sprite_x;
+            self.pixel_fifo
+                .fetch_sprite(&fetcher, &self.mapper, &self.video_ram, oam_ram, drop);
+
+            if stall > 0 {
+                self.sprite_stall = stall;
+                return true;
             }
-            88..=452 => {
-                if self.mode == LCDMode::HBlank {
-                    // nothing to do
-                    return None;
-                }
+        }
 
-                if self.x >= 160 {
-                    interrupt = interrupt.or(self.switch_to(LCDMode::HBlank));
-                    let scanline = self.scanline() as usize;
-                    self.print_sprites(oam_ram, scanline);
-                }
+        false
+    }
 
-                // FIXME: every CPU cycle is 2 PPU cycles
-                for _ in 0..2 {
-                    self.fetcher_step(oam_ram);
-                }
-            }
+    fn sprite_color_from_raw(&self, pixel: SpritePixel) -> GrayShade {
+        match (pixel.palette, pixel.color) {
+            (SpritePalette::C0, 0b01) => self.mapper.obp0_palette_01(),
+            (SpritePalette::C0, 0b10) => self.mapper.obp0_palette_10(),
+            (SpritePalette::C0, 0b11) => self.mapper.obp0_palette_11(),
+            (SpritePalette::C1, 0b01) => self.mapper.obp1_palette_01(),
+            (SpritePalette::C1, 0b10) => self.mapper.obp1_palette_10(),
+            (SpritePalette::C1, 0b11) => self.mapper.obp1_palette_11(),
             _ => unreachable!(),
         }
-
-        interrupt
     }
 
-    fn fetcher_step(&mut self, oam_ram: &[u8]) {
-        if self.mapper.bg_window_on() != 1 {
-            self.x += 2;
+    fn render_dot(&mut self, oam_ram: &[u8]) {
+        if self.x >= SCREEN_X {
             return;
         }
 
-        self.pixel_fifo.step(&self.mapper, &self.video_ram, oam_ram);
+        if self.sprite_stall > 0 {
+            // No pixels come out while a sprite is being fetched
+            self.sprite_stall -= 1;
+            return;
+        }
 
-        if !self.pixel_fifo.has_pixels() {
+        if self.fetch_sprites(oam_ram) {
             return;
         }
 
-        for _ in 0..2 {
-            let raw = self.pixel_fifo.pop();
-            if self.x < SCREEN_X {
-                self.background[self.x] = raw;
-            }
-            let color = self.background_color_from_raw(raw);
-            self.write_raw_pixel(self.x, self.scanline() as usize, color);
-            self.x += 1;
-            if self.check_window_x() {
+        let background_on = self.mapper.bg_window_on() == 1;
+        let background = if background_on {
+            self.pixel_fifo.dot(&self.mapper, &self.video_ram, oam_ram);
+
+            if !self.pixel_fifo.has_pixels() {
                 return;
             }
-        }
+
+            let raw = self.pixel_fifo.pop();
+            let (layer, source) = if self.window_drawn {
+                (Layer::Window, PixelSource::Window)
+            } else {
+                (Layer::Background, PixelSource::Background)
+            };
+
+            RawPixel {
+                // Hidden layers look like they're made of color 0
+                color: if self.render_options.visible(layer) {
+                    raw
+                } else {
+                    0
+                },
+                source,
+                palette: self.mapper.bgp.get(),
+            }
+        } else {
+            // Background and window are blank
+            RawPixel::blank()
+        };
+
+        let sprite = self.pixel_fifo.pop_sprite();
+        let (color, pixel) = if sprite.color != 0
+            && self.mapper.obj_sprite_display() == 1
+            && self.render_options.sprites
+            && (!sprite.below_bg || background.color == 0)
+        {
+            let (source, palette) = match sprite.palette {
+                SpritePalette::C0 => (PixelSource::Sprite0, self.mapper.obp0.get()),
+                SpritePalette::C1 => (PixelSource::Sprite1, self.mapper.obp1.get()),
+            };
+
+            let pixel = RawPixel {
+                color: sprite.color,
+                source,
+                palette,
+            };
+
+            (self.sprite_color_from_raw(sprite), pixel)
+        } else if background_on {
+            (self.background_color_from_raw(background.color), background)
+        } else {
+            (GrayShade::C00, background)
+        };
+
+        // The outlines are drawn as black sprite pixels
+        let (color, pixel) = if self.render_options.sprite_boxes && self.on_sprite_box(oam_ram) {
+            let outline = RawPixel {
+                color: 0b11,
+                source: PixelSource::Sprite0,
+                palette: 0xFF,
+            };
+            (GrayShade::C11, outline)
+        } else {
+            (color, pixel)
+        };
+
+        let scanline = self.scanline() as usize;
+        self.write_raw_pixel(self.x, scanline, color, pixel);
+        self.x += 1;
+        self.check_window_x();
     }
 
-    fn print_sprites(&mut self, oam_ram: &[u8], scanline: usize) {
-        let mut sprite_module = SpriteModule {
+    /// Checks if the current pixel is on the border of one of the sprites
+    /// on this line.
+    fn on_sprite_box(&self, oam_ram: &[u8]) -> bool {
+        let sprite_module = SpriteModule {
             oam_ram,
-            video_ram: &self.video_ram,
             mapper: &self.mapper,
-            background: &self.background,
-            screen_buffer: &mut self.screen_buffer,
         };
 
-        sprite_module.print_sprites(scanline, self.visible_sprites, self.visible_sprites_len);
+        let height = match self.mapper.sprite_size() {
+            SpriteSize::C8by8 => 8,
+            SpriteSize::C8by16 => 16,
+        };
+
+        let x = self.x + 8;
+        let row = self.scanline() as usize + 16;
+
+        self.visible_sprites[0..self.visible_sprites_len]
+            .iter()
+            .any(|&id| {
+                let sprite_x = sprite_module.sprite_x(id);
+                let sprite_y = sprite_module.sprite_y(id);
+
+                if x < sprite_x || x >= sprite_x + 8 {
+                    return false;
+                }
+
+                x == sprite_x
+                    || x == sprite_x + 7
+                    || row == sprite_y
+                    || row == sprite_y + height - 1
+            })
+    }
+
+    fn end_lcd_transfer(&mut self, oam_ram: &[u8]) {
+        // The end of mode 3 is computed in advance, this takes care of the
+        // odd pixel the pipeline didn't output in time.
+        while self.x < SCREEN_X {
+            self.render_dot(oam_ram);
+        }
+
+        if self.window_drawn {
+            self.window_line += 1;
+            self.window_drawn = false;
+        }
+
+        self.set_mode(LCDMode::HBlank);
     }
 }
 
 struct SpriteModule<'a> {
     oam_ram: &'a [u8],
-    video_ram: &'a [u8],
-    background: &'a [u8],
     mapper: &'a VideoMemoryMapper,
-    screen_buffer: &'a mut ScreenBuffer,
 }
 
 impl<'a> SpriteModule<'a> {
@@ -467,39 +855,6 @@ impl<'a> SpriteModule<'a> {
         (visible_sprites, visible_sprites_len)
     }
 
-    fn print_sprites(
-        &mut self,
-        scanline: usize,
-        visible_sprites: [usize; 10],
-        visible_sprites_len: usize,
-    ) {
-        for x in 0..SCREEN_X {
-            for i in 0..visible_sprites_len {
-                let id = visible_sprites[i];
-                if !self.is_sprite_horizontally_visible(id, x) {
-                    continue;
-                }
-
-                let flags = self.sprite_flags(id);
-
-                let color = self.sprite_value_at(
-                    id,
-                    x + 8 - self.sprite_x(id),
-                    scanline + 16 - self.sprite_y(id),
-                );
-
-                if color != GrayShade::Transparent {
-                    if !flags.below_bg || self.background[x] == 0 {
-                        if x < SCREEN_X && scanline < SCREEN_Y {
-                            self.screen_buffer[scanline][x] = color;
-                        }
-                    }
-                    break;
-                }
-            }
-        }
-    }
-
     #[inline]
     fn sprite_y(&self, id: usize) -> usize {
         self.oam_ram[id * 4] as usize
@@ -510,11 +865,6 @@ impl<'a> SpriteModule<'a> {
         self.oam_ram[id * 4 + 1] as usize
     }
 
-    #[inline]
-    fn sprite_tile_index(&self, id: usize) -> usize {
-        self.oam_ram[id * 4 + 2] as usize
-    }
-
     #[inline]
     fn sprite_flags(&self, id: usize) -> SpriteFlags {
         let v = self.oam_ram[id * 4 + 3];
@@ -538,50 +888,6 @@ impl<'a> SpriteModule<'a> {
             SpriteSize::C8by16 => scanline + 16 >= y && scanline < y,
         }
     }
-
-    fn is_sprite_horizontally_visible(&self, id: usize, h: usize) -> bool {
-        let x = self.sprite_x(id);
-        h + 8 >= x && h < x
-    }
-
-    fn sprite_value_at(&self, id: usize, x: usize, y: usize) -> GrayShade {
-        let flags = self.sprite_flags(id);
-
-        let x = if flags.x_flip { 7 - x } else { x };
-
-        let y = if !flags.y_flip {
-            y
-        } else {
-            match self.mapper.sprite_size() {
-                SpriteSize::C8by8 => 7 - y,
-                SpriteSize::C8by16 => 15 - y,
-            }
-        };
-
-        let tile_index = match self.mapper.sprite_size() {
-            SpriteSize::C8by8 => self.sprite_tile_index(id),
-            SpriteSize::C8by16 => self.sprite_tile_index(id) & 0xFE,
-        };
-
-        let color = pattern_value(&self.video_ram, tile_index * 16, x, y);
-
-        match &flags.palette {
-            &SpritePalette::C0 => match color {
-                0b00 => GrayShade::Transparent,
-                0b01 => self.mapper.obp0_palette_01(),
-                0b10 => self.mapper.obp0_palette_10(),
-                0b11 => self.mapper.obp0_palette_11(),
-                _ => unreachable!(),
-            },
-            &SpritePalette::C1 => match color {
-                0b00 => GrayShade::Transparent,
-                0b01 => self.mapper.obp1_palette_01(),
-                0b10 => self.mapper.obp1_palette_10(),
-                0b11 => self.mapper.obp1_palette_11(),
-                _ => unreachable!(),
-            },
-        }
-    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -639,6 +945,61 @@ impl Fifo {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct SpritePixel {
+    // 0 is transparent
+    color: u8,
+    palette: SpritePalette,
+    below_bg: bool,
+}
+
+impl SpritePixel {
+    fn transparent() -> SpritePixel {
+        SpritePixel {
+            color: 0,
+            palette: SpritePalette::C0,
+            below_bg: false,
+        }
+    }
+}
+
+// Holds the pixels of the sprites fetched so far, the first one is the
+// pixel that's mixed with the next background pixel.
+struct SpriteFifo {
+    queue: [SpritePixel; 8],
+}
+
+impl SpriteFifo {
+    fn new() -> SpriteFifo {
+        SpriteFifo {
+            queue: [SpritePixel::transparent(); 8],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.queue = [SpritePixel::transparent(); 8];
+    }
+
+    /// Sprites fetched earlier have priority, so the new pixels only go
+    /// where the FIFO is transparent.
+    fn merge(&mut self, pixels: [SpritePixel; 8], drop: usize) {
+        for (slot, &pixel) in self.queue.iter_mut().zip(&pixels[drop..]) {
+            if slot.color == 0 {
+                *slot = pixel;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> SpritePixel {
+        let v = self.queue[0];
+        for i in 1..8 {
+            self.queue[i - 1] = self.queue[i];
+        }
+        self.queue[7] = SpritePixel::transparent();
+        v
+    }
+}
+
 struct PipelineUnit {
     pattern: u8,
     tile0: u8,
@@ -716,9 +1077,52 @@ impl Fetcher for BackgroundFetcher {
     }
 }
 
+struct SpriteFetcher {
+    id: usize,
+    // Row of the sprite on the current scanline
+    y: usize,
+    flags: SpriteFlags,
+}
+
+impl SpriteFetcher {
+    fn new(id: usize, y: usize, flags: SpriteFlags) -> SpriteFetcher {
+        SpriteFetcher { id, y, flags }
+    }
+}
+
+impl Fetcher for SpriteFetcher {
+    fn tile(
+        &self,
+        mapper: &VideoMemoryMapper,
+        pattern: u8,
+        video_ram: &[u8],
+        _oam_ram: &[u8],
+        tile: usize,
+    ) -> u8 {
+        let (pattern, height) = match mapper.sprite_size() {
+            SpriteSize::C8by8 => (pattern as usize, 8),
+            SpriteSize::C8by16 => (pattern as usize & 0xFE, 16),
+        };
+
+        let y = if self.flags.y_flip {
+            height - 1 - self.y
+        } else {
+            self.y
+        };
+
+        video_ram[pattern * 16 + y * 2 + tile]
+    }
+    fn pattern(&self, _video_ram: &[u8], oam_ram: &[u8]) -> u8 {
+        oam_ram[self.id * 4 + 2]
+    }
+    fn next_step(&mut self) {}
+}
+
 struct PixelPipeline {
     fifo: Fifo,
+    sprite_fifo: SpriteFifo,
     stage: PipelineStage,
+    idle_dot: bool,
     drop: usize,
     current: PipelineUnit,
     fetcher: Box<dyn Fetcher>,
@@ -729,7 +1133,9 @@ impl PixelPipeline {
         PixelPipeline {
             fetcher: Box::new(NullFetcher {}),
             fifo: Fifo::new(),
+            sprite_fifo: SpriteFifo::new(),
             stage: PipelineStage::ReadPattern,
+            idle_dot: false,
             drop: 0,
             current: PipelineUnit {
                 pattern: 0,
@@ -743,9 +1149,20 @@ impl PixelPipeline {
         self.drop = drop;
         self.fifo.reset();
         self.stage = PipelineStage::ReadPattern;
+        self.idle_dot = false;
         self.fetcher = Box::new(fetcher);
     }
 
+    /// Every step of the fetcher takes 2 cycles, this counts the
+    /// cycles the pipeline actually runs for, so it stays in step after
+    /// the pixel output is stopped by a sprite.
+    fn dot(&mut self, mapper: &VideoMemoryMapper, video_ram: &[u8], oam_ram: &[u8]) {
+        self.idle_dot = !self.idle_dot;
+        if !self.idle_dot {
+            self.step(mapper, video_ram, oam_ram);
+        }
+    }
+
     fn push_pixels(&mut self) {
         let l = self.current.tile0;
         let h = self.current.tile1;
@@ -801,6 +1218,37 @@ impl PixelPipeline {
         self.fifo.pop()
     }
 
+    fn reset_sprites(&mut self) {
+        self.sprite_fifo.reset();
+    }
+
+    fn fetch_sprite(
+        &mut self,
+        fetcher: &SpriteFetcher,
+        mapper: &VideoMemoryMapper,
+        video_ram: &[u8],
+        oam_ram: &[u8],
+        drop: usize,
+    ) {
+        let pattern = fetcher.pattern(video_ram, oam_ram);
+        let l = fetcher.tile(mapper, pattern, video_ram, oam_ram, 0);
+        let h = fetcher.tile(mapper, pattern, video_ram, oam_ram, 1);
+
+        let mut pixels = [SpritePixel::transparent(); 8];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let bit = if fetcher.flags.x_flip { i } else { 7 - i };
+            pixel.color = ((l >> bit) & 0b1) + (((h >> bit) & 0b1) << 1);
+            pixel.palette = fetcher.flags.palette;
+            pixel.below_bg = fetcher.flags.below_bg;
+        }
+
+        self.sprite_fifo.merge(pixels, drop);
+    }
+
+    fn pop_sprite(&mut self) -> SpritePixel {
+        self.sprite_fifo.pop()
+    }
+
     fn has_pixels(&self) -> bool {
         self.fifo.size() > 8
     }
@@ -895,6 +1343,7 @@ memory_mapper! {
 #[cfg(test)]
 mod test {
     use super::*;
+    use hardware::cpu::Handler;
 
     #[test]
     fn fifo() {
@@ -934,4 +1383,230 @@ mod test {
         assert_eq!(fifo.pop(), 101);
         assert_eq!(fifo.size(), 0);
     }
+
+    fn step_to_line(ppu: &mut Ppu, line: usize) {
+        let oam_ram = [0; 160];
+        while ppu.cycles != line * SCANLINE_CYCLES {
+            ppu.cpu_step(&oam_ram);
+        }
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut ppu = Ppu::new();
+
+        // Tile 1 is all C11 and tile 2 is all C01
+        for i in 0..8 {
+            ppu.write(0x8010 + i * 2, 0xFF);
+            ppu.write(0x8011 + i * 2, 0xFF);
+            ppu.write(0x8020 + i * 2, 0xFF);
+            ppu.write(0x8021 + i * 2, 0x00);
+        }
+
+        // The first row of the window is made of tile 1, the second of tile 2
+        for i in 0..32 {
+            ppu.write(0x9C00 + i, 0x01);
+            ppu.write(0x9C20 + i, 0x02);
+        }
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 7);
+
+        let window_on = 0b1111_0001;
+        let window_off = 0b1101_0001;
+
+        ppu.write(0xFF40, window_on);
+        step_to_line(&mut ppu, 4);
+        ppu.write(0xFF40, window_off);
+        step_to_line(&mut ppu, 10);
+        ppu.write(0xFF40, window_on);
+        step_to_line(&mut ppu, 20);
+
+        // Rows 0 to 3 of the window are drawn on lines 0 to 3, the window is
+        // then resumed on line 10 from row 4.
+        assert_eq!(ppu.get_screen()[3][0], GrayShade::C11);
+        assert_eq!(ppu.get_screen()[6][0], GrayShade::C00);
+        assert_eq!(ppu.get_screen()[13][0], GrayShade::C11);
+        assert_eq!(ppu.get_screen()[14][0], GrayShade::C01);
+    }
+
+    #[test]
+    fn window_x_edge_cases() {
+        let mut ppu = Ppu::new();
+
+        for i in 0..8 {
+            ppu.write(0x8010 + i * 2, 0xFF);
+            ppu.write(0x8011 + i * 2, 0xFF);
+        }
+        for i in 0..32 {
+            ppu.write(0x9C00 + i, 0x01);
+        }
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0b1111_0001);
+
+        // WX = 0 starts the window at the first pixel
+        ppu.write(0xFF4B, 0);
+        step_to_line(&mut ppu, 1);
+        assert_eq!(ppu.get_screen()[0][0], GrayShade::C11);
+
+        // WX = 166 only shows the last pixel
+        ppu.write(0xFF4B, 166);
+        step_to_line(&mut ppu, 2);
+        assert_eq!(ppu.get_screen()[1][158], GrayShade::C00);
+        assert_eq!(ppu.get_screen()[1][159], GrayShade::C11);
+
+        // Past that the window is hidden and its rows don't advance
+        ppu.write(0xFF4B, 167);
+        step_to_line(&mut ppu, 3);
+        assert_eq!(ppu.get_screen()[2][159], GrayShade::C00);
+        assert_eq!(ppu.window_line, 2);
+    }
+
+    #[test]
+    fn lyc_zero_interrupt() {
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF45, 0);
+        ppu.write(0xFF41, 0b0100_0000);
+        ppu.write(0xFF40, 0b1000_0000);
+        step_to_line(&mut ppu, 1);
+        while ppu.check_interrupts().is_some() {}
+
+        // LY = 0 is seen on line 153 already, line 0 doesn't raise the
+        // interrupt a second time
+        let oam_ram = [0; 160];
+        let mut interrupts = 0;
+        for _ in 0..SCREEN_CYCLES / cpu::CYCLES_PER_STEP {
+            ppu.cpu_step(&oam_ram);
+            while let Some(interrupt) = ppu.check_interrupts() {
+                if let cpu::Interrupt::Stat = interrupt {
+                    interrupts += 1;
+                }
+            }
+        }
+        assert_eq!(interrupts, 1);
+    }
+
+    #[test]
+    fn sprite_fifo_mixing() {
+        let mut ppu = Ppu::new();
+
+        // Tile 1 is all C11 and tile 2 is all C01
+        for i in 0..8 {
+            ppu.write(0x8010 + i * 2, 0xFF);
+            ppu.write(0x8011 + i * 2, 0xFF);
+            ppu.write(0x8020 + i * 2, 0xFF);
+            ppu.write(0x8021 + i * 2, 0x00);
+        }
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0b1001_0011);
+
+        // Two overlapping sprites on the first line, and one on the second
+        // line behind the background.
+        let mut oam_ram = [0; 160];
+        oam_ram[0..4].copy_from_slice(&[16, 18, 1, 0]);
+        oam_ram[4..8].copy_from_slice(&[16, 22, 2, 0]);
+        oam_ram[8..12].copy_from_slice(&[17, 8, 1, 0b1000_0000]);
+
+        while ppu.cycles != 2 * SCANLINE_CYCLES {
+            ppu.cpu_step(&oam_ram);
+        }
+
+        let screen = ppu.get_screen();
+
+        // The leftmost sprite wins where they overlap
+        assert_eq!(screen[0][9], GrayShade::C00);
+        assert_eq!(screen[0][10], GrayShade::C11);
+        assert_eq!(screen[0][17], GrayShade::C11);
+        assert_eq!(screen[0][18], GrayShade::C01);
+        assert_eq!(screen[0][21], GrayShade::C01);
+        assert_eq!(screen[0][22], GrayShade::C00);
+
+        // The background is color 0 so the sprite shows through
+        assert_eq!(screen[1][0], GrayShade::C11);
+        assert_eq!(screen[1][8], GrayShade::C00);
+
+        let raw_screen = ppu.get_raw_screen();
+        assert_eq!(
+            raw_screen[0][9],
+            RawPixel {
+                color: 0,
+                source: PixelSource::Background,
+                palette: 0xE4,
+            }
+        );
+        assert_eq!(
+            raw_screen[0][18],
+            RawPixel {
+                color: 0b01,
+                source: PixelSource::Sprite0,
+                palette: 0xE4,
+            }
+        );
+    }
+
+    #[test]
+    fn render_options() {
+        let mut ppu = Ppu::new();
+
+        for i in 0..8 {
+            ppu.write(0x8010 + i * 2, 0xFF);
+            ppu.write(0x8011 + i * 2, 0xFF);
+        }
+        for i in 0..32 {
+            ppu.write(0x9C00 + i, 0x01);
+        }
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4B, 7);
+        ppu.write(0xFF40, 0b1111_0011);
+
+        let mut options = RenderOptions::new();
+        options.set_visible(Layer::Window, false);
+        options.sprite_boxes = true;
+        ppu.set_render_options(options);
+
+        // A transparent sprite on the first two lines
+        let mut oam_ram = [0; 160];
+        oam_ram[0..4].copy_from_slice(&[16, 28, 0, 0]);
+
+        while ppu.cycles != 2 * SCANLINE_CYCLES {
+            ppu.cpu_step(&oam_ram);
+        }
+
+        let screen = ppu.get_screen();
+
+        assert_eq!(screen[1][0], GrayShade::C00);
+        assert_eq!(screen[0][22], GrayShade::C11);
+        assert_eq!(screen[1][20], GrayShade::C11);
+        assert_eq!(screen[1][22], GrayShade::C00);
+        assert_eq!(screen[1][27], GrayShade::C11);
+        assert_eq!(screen[1][28], GrayShade::C00);
+    }
+
+    #[test]
+    fn vram_blocked_during_lcd_transfer() {
+        let mut ppu = Ppu::new();
+        let oam_ram = [0; 160];
+
+        ppu.write(0x8000, 0x12);
+        while ppu.mapper.mode() != LCDMode::LCDTransfer {
+            ppu.cpu_step(&oam_ram);
+        }
+
+        assert_eq!(ppu.read(0x8000), 0xFF);
+        assert!(ppu.oam_read_blocked());
+        assert!(ppu.oam_write_blocked());
+        ppu.write(0x8000, 0x34);
+
+        ppu.set_lenient_access(true);
+        assert_eq!(ppu.read(0x8000), 0x12);
+        assert!(!ppu.oam_read_blocked());
+        assert!(!ppu.oam_write_blocked());
+        ppu.write(0x8000, 0x56);
+        assert_eq!(ppu.read(0x8000), 0x56);
+    }
 }
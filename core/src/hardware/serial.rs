@@ -0,0 +1,246 @@
+use hardware::cpu;
+
+use bitfield::Bitfield;
+
+/// The internal clock runs at 8192 Hz, a bit every 512 T-cycles.
+const CYCLES_PER_BIT: usize = 512;
+const CYCLES_PER_BYTE: usize = 8 * CYCLES_PER_BIT;
+
+/// What the data line reads when nothing drives it.
+pub const DISCONNECTED: u8 = 0xFF;
+
+/**
+ * The other end of the link cable.
+ *
+ * The two sides swap their bytes one transfer at a time: the side that
+ * drives the clock calls `exchange` when its 8 bits have been shifted, the
+ * other side keeps its byte ready with `set_waiting` and is told about the
+ * transfer through `receive`.
+ */
+pub trait LinkPort {
+    /// Sends `byte` with this side's clock, returns the byte of the other
+    /// side or `DISCONNECTED` if it wasn't waiting for a transfer.
+    fn exchange(&mut self, byte: u8) -> u8;
+    /// `byte` is ready to go out as soon as the other side clocks a
+    /// transfer, `None` when not waiting anymore.
+    fn set_waiting(&mut self, byte: Option<u8>);
+    /// The byte sent by the other side if it clocked a transfer since this
+    /// side started waiting.
+    fn receive(&mut self) -> Option<u8>;
+    /// Called on every CPU step, for ports that keep time with the other
+    /// side.
+    fn cpu_step(&mut self) {}
+}
+
+memory_mapper! {
+    name: SerialTransferController,
+    fields: [
+        0xFF01, 0b00000000, transfer_data, 0;
+    ],
+    bitfields: {
+        getters: [
+            0xFF02, 0b01111110, flags, 0, [
+                get_0, shift_clock, u8;
+                get_1, fast_clock, u8;
+                get_7, start_transfer, u8
+            ]
+        ],
+        getter_setters: [
+        ],
+    },
+}
+
+/**
+ * The serial port, SB at 0xFF01 and SC at 0xFF02.
+ *
+ * With the internal clock a transfer takes 8 bits at 8192 Hz and completes
+ * even without a cable, shifting in 0xFF. With the external clock it waits
+ * for the other side, forever if nothing is connected.
+ */
+pub struct SerialTransfer {
+    debug_data: Vec<u8>,
+    mapper: SerialTransferController,
+    port: Option<Box<dyn LinkPort>>,
+    /// T-cycles left in the transfer driven by the internal clock, 0 when
+    /// there's none.
+    cycles: usize,
+    interrupt: bool,
+}
+
+impl SerialTransfer {
+    pub fn new() -> SerialTransfer {
+        SerialTransfer {
+            debug_data: vec![],
+            mapper: SerialTransferController::new(),
+            port: None,
+            cycles: 0,
+            interrupt: false,
+        }
+    }
+
+    pub fn set_link_port(&mut self, port: Option<Box<dyn LinkPort>>) {
+        self.port = port;
+        self.update_waiting();
+    }
+
+    pub fn take_link_port(&mut self) -> Option<Box<dyn LinkPort>> {
+        self.port.take()
+    }
+
+    fn waiting(&self) -> bool {
+        self.mapper.start_transfer() == 1 && self.mapper.shift_clock() == 0
+    }
+
+    fn update_waiting(&mut self) {
+        let byte = if self.waiting() {
+            Some(self.mapper.transfer_data)
+        } else {
+            None
+        };
+
+        if let Some(ref mut port) = self.port {
+            port.set_waiting(byte);
+        }
+    }
+
+    pub fn cpu_step(&mut self) {
+        if let Some(ref mut port) = self.port {
+            port.cpu_step();
+        }
+
+        if self.cycles > 0 {
+            self.cycles -= cpu::CYCLES_PER_STEP;
+            if self.cycles == 0 {
+                let byte = self.mapper.transfer_data;
+                let received = match self.port {
+                    Some(ref mut port) => port.exchange(byte),
+                    None => DISCONNECTED,
+                };
+                self.complete(received);
+            }
+        } else if self.waiting() {
+            let received = self.port.as_mut().and_then(|port| port.receive());
+            if let Some(received) = received {
+                self.complete(received);
+            }
+        }
+    }
+
+    fn complete(&mut self, received: u8) {
+        self.mapper.transfer_data = received;
+        let flags = self.mapper.flags.get();
+        self.mapper.flags.set(flags & 0x7F);
+        self.interrupt = true;
+        self.update_waiting();
+    }
+
+    pub fn check_interrupts(&mut self) -> Option<cpu::Interrupt> {
+        if self.interrupt {
+            self.interrupt = false;
+            Some(cpu::Interrupt::Serial)
+        } else {
+            None
+        }
+    }
+}
+
+impl cpu::Handler for SerialTransfer {
+    fn read(&self, address: u16) -> u8 {
+        self.mapper.read(address)
+    }
+
+    fn write(&mut self, address: u16, v: u8) {
+        if address == 0xFF01 && self.port.is_none() {
+            print!("{}", v as char);
+            // Blargg's test roms use this address to print debug information
+            self.debug_data.push(v);
+        }
+        self.mapper.write(address, v);
+
+        if address == 0xFF02 {
+            self.cycles = if self.mapper.start_transfer() == 1 && self.mapper.shift_clock() == 1 {
+                CYCLES_PER_BYTE
+            } else {
+                0
+            };
+        }
+        self.update_waiting();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hardware::cpu::Handler;
+
+    fn run(serial: &mut SerialTransfer, cycles: usize) {
+        for _ in 0..cycles / cpu::CYCLES_PER_STEP {
+            serial.cpu_step();
+        }
+    }
+
+    struct Echo {
+        waiting: Option<u8>,
+    }
+
+    impl LinkPort for Echo {
+        fn exchange(&mut self, byte: u8) -> u8 {
+            byte ^ 0xFF
+        }
+
+        fn set_waiting(&mut self, byte: Option<u8>) {
+            self.waiting = byte;
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            self.waiting.map(|byte| byte + 1)
+        }
+    }
+
+    #[test]
+    fn internal_clock() {
+        let mut serial = SerialTransfer::new();
+        serial.set_link_port(Some(Box::new(Echo { waiting: None })));
+        serial.write(0xFF01, 0x0F);
+        serial.write(0xFF02, 0x81);
+
+        run(&mut serial, CYCLES_PER_BYTE - 2);
+        assert_eq!(serial.read(0xFF02), 0xFF);
+        assert!(serial.check_interrupts().is_none());
+
+        run(&mut serial, 2);
+        assert_eq!(serial.read(0xFF01), 0xF0);
+        assert_eq!(serial.read(0xFF02), 0x7F);
+        assert!(serial.check_interrupts().is_some());
+        assert!(serial.check_interrupts().is_none());
+    }
+
+    #[test]
+    fn disconnected() {
+        let mut serial = SerialTransfer::new();
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        run(&mut serial, CYCLES_PER_BYTE);
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert!(serial.check_interrupts().is_some());
+
+        // Nobody drives the external clock
+        serial.write(0xFF02, 0x80);
+        run(&mut serial, 4 * CYCLES_PER_BYTE);
+        assert_eq!(serial.read(0xFF02), 0xFE);
+        assert!(serial.check_interrupts().is_none());
+    }
+
+    #[test]
+    fn external_clock() {
+        let mut serial = SerialTransfer::new();
+        serial.set_link_port(Some(Box::new(Echo { waiting: None })));
+        serial.write(0xFF01, 0x41);
+        serial.write(0xFF02, 0x80);
+        run(&mut serial, 2);
+
+        assert_eq!(serial.read(0xFF01), 0x42);
+        assert_eq!(serial.read(0xFF02), 0x7E);
+        assert!(serial.check_interrupts().is_some());
+    }
+}
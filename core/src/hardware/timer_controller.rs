@@ -1,6 +1,6 @@
 use bitfield::Bitfield;
 use hardware::cpu;
-use std::num::Wrapping;
+use hardware::cpu::Handler;
 
 u8_enum! {
     ClockSelect {
@@ -12,10 +12,39 @@ u8_enum! {
     }
 }
 
+impl ClockSelect {
+    /// The bit of the system counter that clocks TIMA on its falling edge.
+    fn counter_bit(self) -> u16 {
+        match self {
+            ClockSelect::C1024 => 1 << 9,
+            ClockSelect::C16 => 1 << 3,
+            ClockSelect::C64 => 1 << 5,
+            ClockSelect::C256 => 1 << 7,
+        }
+    }
+}
+
+/** After TIMA overflows it reads 0x00 for one M-cycle, then TMA is
+ * copied into it and the interrupt is requested. During the M-cycle in
+ * which the copy happens writes to TIMA are ignored and writes to TMA
+ * go through to TIMA too. */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Reload {
+    Idle,
+    Overflowed(usize),
+    Reloading(usize),
+}
+
+const RELOAD_CYCLES: usize = 4;
+
 pub struct TimerController {
-    clock: usize,
-    last_clock: usize,
-    divider_counter: usize,
+    /// The DMG has a single 16-bit counter incremented every clock,
+    /// DIV is just its upper 8 bits.
+    system_counter: u16,
+    reload: Reload,
+    /// TIMA and the reload right before the last tick of the system
+    /// counter, to replay it when TAC is written.
+    before_tick: (u8, Reload),
 
     mapper: TimerMemoryMapper,
 }
@@ -23,85 +52,141 @@ pub struct TimerController {
 impl TimerController {
     pub fn new() -> TimerController {
         TimerController {
-            clock: 0,
-            last_clock: 0,
-            divider_counter: 0,
+            // Value left behind by the DMG boot ROM
+            system_counter: 0xABCC,
+            reload: Reload::Idle,
+            before_tick: (0, Reload::Idle),
 
             mapper: TimerMemoryMapper::new(),
         }
     }
 
-    pub fn cpu_step(&mut self) -> Option<cpu::Interrupt> {
-        self.last_clock += cpu::CYCLES_PER_STEP;
-        self.divider_counter += cpu::CYCLES_PER_STEP;
+    /// The signal TIMA is clocked by: the selected counter bit ANDed with
+    /// the enable bit. TIMA increments whenever this goes from 1 to 0, which
+    /// is why writing to DIV or TAC can increment it too.
+    fn timer_signal(&self) -> bool {
+        self.mapper.timer_enabled() == 1
+            && self.system_counter & self.mapper.clock_select().counter_bit() != 0
+    }
 
+    pub fn cpu_step(&mut self) -> Option<cpu::Interrupt> {
         let mut interrupt = None;
-        if self.divider_counter == 256 {
-            self.mapper.divider = (Wrapping(self.mapper.divider) + Wrapping(1)).0;
-            if self.mapper.divider == 0x00 {
-                interrupt = Some(cpu::Interrupt::Timer);
-            }
-            self.divider_counter = 0;
-        }
-
-        while self.last_clock >= 16 {
-            self.last_clock -= 16;
 
-            self.clock = (Wrapping(self.clock) + Wrapping(1)).0;
-            if self.inc_clock() {
+        self.reload = match self.reload {
+            Reload::Idle => Reload::Idle,
+            Reload::Overflowed(cycles) if cycles > cpu::CYCLES_PER_STEP => {
+                Reload::Overflowed(cycles - cpu::CYCLES_PER_STEP)
+            }
+            Reload::Overflowed(_) => {
+                self.mapper.timer = self.mapper.modulo;
                 interrupt = Some(cpu::Interrupt::Timer);
+                Reload::Reloading(RELOAD_CYCLES)
             }
-        }
+            Reload::Reloading(cycles) if cycles > cpu::CYCLES_PER_STEP => {
+                Reload::Reloading(cycles - cpu::CYCLES_PER_STEP)
+            }
+            Reload::Reloading(_) => Reload::Idle,
+        };
 
-        if self.mapper.timer_enabled() == 0 {
-            return None;
-        }
+        self.before_tick = (self.mapper.timer, self.reload);
+        self.tick();
 
         interrupt
     }
 
-    fn inc_clock(&mut self) -> bool {
-        let should_increment = match self.mapper.clock_select() {
-            ClockSelect::C16 => true,
-            ClockSelect::C64 => (self.clock % 4) == 0,
-            ClockSelect::C256 => (self.clock % 16) == 0,
-            ClockSelect::C1024 => (self.clock % 64) == 0,
-        };
+    fn tick(&mut self) {
+        let signal = self.timer_signal();
+        self.system_counter = self
+            .system_counter
+            .wrapping_add(cpu::CYCLES_PER_STEP as u16);
+        self.detect_falling_edge(signal);
+    }
 
-        if should_increment {
-            if self.mapper.timer == 0xFF {
-                self.mapper.timer = self.mapper.modulo;
-                true
-            } else {
-                self.mapper.timer = self.mapper.timer + 1;
-                false
-            }
+    /// Writes land at the end of the M-cycle but TAC changes half way
+    /// through it, before the last tick of the system counter. Enabling
+    /// the timer right as the selected bit falls still increments TIMA.
+    fn write_control(&mut self, v: u8) {
+        let (timer, reload) = self.before_tick;
+        self.mapper.timer = timer;
+        self.reload = reload;
+        self.system_counter = self
+            .system_counter
+            .wrapping_sub(cpu::CYCLES_PER_STEP as u16);
+
+        let signal = self.timer_signal();
+        self.mapper.write(0xFF07, v);
+        self.detect_falling_edge(signal);
+        self.tick();
+    }
+
+    fn detect_falling_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.timer_signal() {
+            self.inc_timer();
+        }
+    }
+
+    fn inc_timer(&mut self) {
+        if self.mapper.timer == 0xFF {
+            self.mapper.timer = 0x00;
+            self.reload = Reload::Overflowed(RELOAD_CYCLES);
         } else {
-            false
+            self.mapper.timer += 1;
+        }
+    }
+}
+
+impl cpu::Handler for TimerController {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => (self.system_counter >> 8) as u8,
+            _ => self.mapper.read(address),
         }
     }
 
-    pub fn write_callback(&mut self, address: u16) {
+    fn write(&mut self, address: u16, v: u8) {
+        if address == 0xFF07 {
+            self.write_control(v);
+            return;
+        }
+
+        let signal = self.timer_signal();
+
         match address {
             0xFF04 => {
-                self.mapper.divider = 0;
-                self.divider_counter = 0;
+                // Any write resets the whole system counter
+                self.system_counter = 0;
+            }
+            0xFF05 => match self.reload {
+                // Writing during the overflow cycle cancels the reload
+                Reload::Overflowed(_) => {
+                    self.reload = Reload::Idle;
+                    self.mapper.write(address, v);
+                }
+                Reload::Reloading(_) => {}
+                Reload::Idle => self.mapper.write(address, v),
+            },
+            0xFF06 => {
+                self.mapper.write(address, v);
+                if let Reload::Reloading(_) = self.reload {
+                    self.mapper.timer = v;
+                }
             }
-            _ => {}
+            _ => self.mapper.write(address, v),
         }
+
+        self.detect_falling_edge(signal);
     }
 }
 
 memory_mapper! {
     name: TimerMemoryMapper,
     fields: [
-        0xFF04, 0b00000000, divider, 0;
         0xFF05, 0b00000000, timer,   0;
         0xFF06, 0b00000000, modulo,  0;
     ],
     bitfields: {
         getters: [
-            0xFF07, 0b00000111, control, 0, [
+            0xFF07, 0b11111000, control, 0, [
                 get_01, clock_select,  ClockSelect;
                 get_2,  timer_enabled, u8
             ]
@@ -109,9 +194,3 @@ memory_mapper! {
         getter_setters: [],
     },
 }
-
-memory_handler! {
-    parent: TimerController,
-    mapper: mapper,
-    callback: write_callback,
-}
@@ -0,0 +1,163 @@
+use hardware::apu::CPU_FREQUENCY;
+use hardware::cpu;
+
+/// VGM files count time in samples at this rate, whatever the chip.
+const VGM_RATE: u64 = 44100;
+
+/// The 1.71 header, the commands start right after it.
+const HEADER_SIZE: usize = 0x100;
+
+const WAIT: u8 = 0x61;
+const WAIT_NTSC_FRAME: u8 = 0x62;
+const WAIT_PAL_FRAME: u8 = 0x63;
+/// 0x70 to 0x7F wait from 1 to 16 samples.
+const WAIT_SHORT: u8 = 0x70;
+const END: u8 = 0x66;
+const GB_DMG_WRITE: u8 = 0xB3;
+
+/**
+ * Logs the writes to the APU registers as a VGM 1.71 file, that chiptune
+ * players can play back.
+ *
+ * Time is kept in T-cycles and turned into VGM samples only when writing
+ * the waits, so that rounding errors don't add up.
+ */
+pub struct VgmLogger {
+    commands: Vec<u8>,
+    /// T-cycles since the log started.
+    clock: u64,
+    /// VGM samples waited so far.
+    samples: u64,
+}
+
+impl VgmLogger {
+    pub fn new() -> VgmLogger {
+        VgmLogger {
+            commands: vec![],
+            clock: 0,
+            samples: 0,
+        }
+    }
+
+    pub fn cpu_step(&mut self) {
+        self.clock += cpu::CYCLES_PER_STEP as u64;
+    }
+
+    /** Logs a write to one of the registers between 0xFF10 and 0xFF3F. */
+    pub fn write(&mut self, address: u16, v: u8) {
+        self.wait();
+        self.commands
+            .extend_from_slice(&[GB_DMG_WRITE, (address - 0xFF10) as u8, v]);
+    }
+
+    /** Catches up with the clock. */
+    fn wait(&mut self) {
+        let target = self.clock * VGM_RATE / CPU_FREQUENCY as u64;
+
+        while self.samples < target {
+            let pending = target - self.samples;
+            let waited = match pending {
+                1..=16 => {
+                    self.commands.push(WAIT_SHORT + pending as u8 - 1);
+                    pending
+                }
+                735 => {
+                    self.commands.push(WAIT_NTSC_FRAME);
+                    pending
+                }
+                882 => {
+                    self.commands.push(WAIT_PAL_FRAME);
+                    pending
+                }
+                _ => {
+                    let samples = pending.min(0xFFFF);
+                    self.commands.push(WAIT);
+                    self.commands
+                        .extend_from_slice(&(samples as u16).to_le_bytes());
+                    samples
+                }
+            };
+
+            self.samples += waited;
+        }
+    }
+
+    /** Ends the log, returns the whole file. */
+    pub fn finish(mut self) -> Vec<u8> {
+        self.wait();
+        self.commands.push(END);
+
+        let mut file = vec![0; HEADER_SIZE];
+        let size = HEADER_SIZE + self.commands.len();
+        write_u32(&mut file, 0x00, u32::from_le_bytes(*b"Vgm "));
+        // Offsets are relative to where they are stored
+        write_u32(&mut file, 0x04, size as u32 - 0x04);
+        write_u32(&mut file, 0x08, 0x171);
+        write_u32(&mut file, 0x18, self.samples as u32);
+        write_u32(&mut file, 0x34, HEADER_SIZE as u32 - 0x34);
+        write_u32(&mut file, 0x80, CPU_FREQUENCY as u32);
+
+        file.extend_from_slice(&self.commands);
+        file
+    }
+}
+
+fn write_u32(data: &mut [u8], offset: usize, v: u32) {
+    data[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(logger: &mut VgmLogger, cycles: u64) {
+        for _ in 0..cycles / cpu::CYCLES_PER_STEP as u64 {
+            logger.cpu_step();
+        }
+    }
+
+    #[test]
+    fn header() {
+        let mut logger = VgmLogger::new();
+        logger.write(0xFF26, 0x80);
+        let file = logger.finish();
+
+        assert_eq!(&file[0..4], b"Vgm ");
+        assert_eq!(&file[0x04..0x08], &[0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(&file[0x08..0x0C], &[0x71, 0x01, 0x00, 0x00]);
+        assert_eq!(&file[0x34..0x38], &[0xCC, 0x00, 0x00, 0x00]);
+        // DMG clock
+        assert_eq!(&file[0x80..0x84], &[0x00, 0x00, 0x40, 0x00]);
+        assert_eq!(&file[0x100..], &[0xB3, 0x16, 0x80, 0x66]);
+    }
+
+    #[test]
+    fn waits_follow_the_clock() {
+        let mut logger = VgmLogger::new();
+        // A frame, a bit more than 735 samples
+        run(&mut logger, 70224);
+        logger.write(0xFF24, 0x77);
+        // Short waits
+        run(&mut logger, 400);
+        logger.write(0xFF25, 0xFF);
+        // Less than a sample apart, only the first one crosses into the
+        // next sample
+        run(&mut logger, 60);
+        logger.write(0xFF25, 0x00);
+        run(&mut logger, 60);
+        logger.write(0xFF25, 0xFF);
+        // More than a single wait command can hold
+        run(&mut logger, 8 * 1024 * 1024);
+        let file = logger.finish();
+
+        assert_eq!(
+            &file[0x100..0x113],
+            &[
+                0x61, 0xE2, 0x02, 0xB3, 0x14, 0x77, 0x73, 0xB3, 0x15, 0xFF, 0x70, 0xB3, 0x15, 0x00,
+                0xB3, 0x15, 0xFF, 0x61, 0xFF
+            ]
+        );
+        // Exactly two seconds more
+        assert_eq!(&file[0x18..0x1C], &(738 + 4 + 1 + 88200u32).to_le_bytes());
+    }
+}
@@ -1,16 +1,35 @@
 #[allow(dead_code)]
 mod bitfield;
+mod colorization;
 mod emulator;
+mod gbs;
 mod hardware;
+mod link;
+mod palette;
+mod printer;
+mod recorder;
 
-pub use self::emulator::{Emulator, AUDIO_BUFFER_SIZE};
+pub use self::colorization::{ButtonCombo, Colorization};
+pub use self::emulator::Emulator;
+pub use self::gbs::Gbs;
 pub use self::hardware::apu::{
-    AudioBuffer, AudioLineView, Channel1View, Channel2View, Channel3View, Channel4View,
-    NoisePattern,
+    AudioBuffer, AudioLineView, Channel, Channel1View, Channel2View, Channel3View, Channel4View,
+    MixOptions, NoisePattern, MAX_RATE_ADJUSTMENT,
 };
 pub use self::hardware::cpu::{Cpu, Hardware, Interrupt, OpCode};
 pub use self::hardware::handler_holder::Key;
-pub use self::hardware::ppu::{GrayShade, ScreenBuffer, SCREEN_X, SCREEN_Y};
+pub use self::hardware::ppu::{
+    GrayShade, Layer, PixelSource, RawPixel, RawScreenBuffer, RenderOptions, ScreenBuffer,
+    SCREEN_X, SCREEN_Y,
+};
+pub use self::hardware::serial::LinkPort;
+pub use self::link::{serial_test_rom, BgbLink, CableEnd, LinkCable};
+pub use self::palette::{
+    builtin_palette, parse_palette, FrameBlender, FrameConverter, FrameFormat, LcdResponse, Rgb,
+    DEFAULT_PALETTE, DMG_PALETTE, GB_POCKET_PALETTE, GRAYSCALE_PALETTE,
+};
+pub use self::printer::{Printer, PrinterOutput, Printout, PRINT_WIDTH};
+pub use self::recorder::{AudioRecorder, WavWriter};
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,386 @@
+use hardware::serial::{LinkPort, DISCONNECTED};
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+const VERSION: u8 = 1;
+const SYNC1: u8 = 104;
+const SYNC2: u8 = 105;
+const SYNC3: u8 = 106;
+const STATUS: u8 = 108;
+const WANT_DISCONNECT: u8 = 109;
+
+/// Status bit, the emulation is running.
+const STATUS_RUNNING: u8 = 0b001;
+
+/// Timestamps count 2 MiHz ticks, a CPU step, in 31 bits.
+const TIMESTAMP_MASK: u32 = 0x7FFF_FFFF;
+
+/// How often the timestamp is sent to the other side, about 120us. A
+/// power of two.
+const SYNC_INTERVAL: u32 = 256;
+/// How far ahead of the other side the emulation can get before waiting
+/// for it. The side that's ahead answers transfers late, this has to stay
+/// well below the time games give each other to get ready for the next
+/// byte.
+const MAX_LEAD: i32 = 4 * SYNC_INTERVAL as i32;
+
+/// Every message of the protocol is 8 bytes: the command, three bytes
+/// that depend on it and a timestamp.
+#[derive(Clone, Copy)]
+struct Packet {
+    command: u8,
+    b2: u8,
+    b3: u8,
+    b4: u8,
+    timestamp: u32,
+}
+
+impl Packet {
+    fn new(command: u8, b2: u8, b3: u8, timestamp: u32) -> Packet {
+        Packet {
+            command,
+            b2,
+            b3,
+            b4: 0,
+            timestamp,
+        }
+    }
+
+    fn from_bytes(data: &[u8; 8]) -> Packet {
+        Packet {
+            command: data[0],
+            b2: data[1],
+            b3: data[2],
+            b4: data[3],
+            timestamp: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+        }
+    }
+
+    fn read(stream: &mut TcpStream) -> Result<Packet, String> {
+        let mut data = [0; 8];
+        stream.read_exact(&mut data).map_err(|e| e.to_string())?;
+        Ok(Packet::from_bytes(&data))
+    }
+
+    /// Whether the timestamp means anything.
+    fn has_timestamp(&self) -> bool {
+        self.command == SYNC1 || (self.command == SYNC3 && self.b2 == 0)
+    }
+
+    fn to_bytes(self) -> [u8; 8] {
+        let t = self.timestamp.to_le_bytes();
+        [
+            self.command,
+            self.b2,
+            self.b3,
+            self.b4,
+            t[0],
+            t[1],
+            t[2],
+            t[3],
+        ]
+    }
+}
+
+/// How far `a` is ahead of `b`, negative if behind.
+fn ahead(a: u32, b: u32) -> i32 {
+    // Sign extend the 31 bits difference
+    ((a.wrapping_sub(b) << 1) as i32) >> 1
+}
+
+/**
+ * A link cable over TCP, speaking the BGB link protocol 1.4 so that the
+ * other side can be another gb-rust or BGB itself.
+ *
+ * Both sides send their timestamps and the one that gets too far ahead
+ * waits for the other. A transfer with the internal clock waits for the
+ * answer, which the other side only gives once its clock reached the
+ * time of the transfer, the same order of events as with a real cable.
+ */
+pub struct BgbLink {
+    stream: TcpStream,
+    packets: Receiver<Packet>,
+    connected: bool,
+    /// Our timestamp, the CPU steps since connecting.
+    clock: u32,
+    /// The timestamp of the other side, in our time.
+    remote_clock: u32,
+    /// What to add to our timestamps to get the ones of the other side.
+    offset: u32,
+    /// A transfer started by the other side that we haven't reached yet,
+    /// the byte and when in our time.
+    pending: Option<(u8, u32)>,
+    waiting: Option<u8>,
+    received: Option<u8>,
+}
+
+impl BgbLink {
+    pub fn connect(address: &str) -> Result<BgbLink, String> {
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Could not connect to '{}': {}", address, e))?;
+        BgbLink::new(stream)
+    }
+
+    /// Waits for the other side to connect.
+    pub fn listen(address: &str) -> Result<BgbLink, String> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format!("Could not listen on '{}': {}", address, e))?;
+        let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        BgbLink::new(stream)
+    }
+
+    /** Checks that the other side speaks the same protocol. */
+    pub fn new(mut stream: TcpStream) -> Result<BgbLink, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+
+        stream
+            .write_all(&Packet::new(VERSION, 1, 4, 0).to_bytes())
+            .map_err(|e| e.to_string())?;
+
+        let remote = Packet::read(&mut stream)?;
+        if remote.command != VERSION || (remote.b2, remote.b3, remote.b4) != (1, 4, 0) {
+            return Err("The other side doesn't speak the BGB link protocol 1.4.".to_string());
+        }
+
+        // Both sides send their timestamp before running, the first one of
+        // the other side is when our clock starts
+        stream
+            .write_all(&Packet::new(SYNC3, 0, 0, 0).to_bytes())
+            .map_err(|e| e.to_string())?;
+        let mut early = vec![];
+        loop {
+            let packet = Packet::read(&mut stream)?;
+            early.push(packet);
+            if packet.has_timestamp() {
+                break;
+            }
+        }
+
+        let mut reader = stream.try_clone().map_err(|e| e.to_string())?;
+        let (sender, packets) = mpsc::channel();
+        thread::spawn(move || {
+            let mut data = [0; 8];
+            while reader.read_exact(&mut data).is_ok() {
+                if sender.send(Packet::from_bytes(&data)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut link = BgbLink {
+            stream,
+            packets,
+            connected: true,
+            clock: 0,
+            remote_clock: 0,
+            offset: early[early.len() - 1].timestamp,
+            pending: None,
+            waiting: None,
+            received: None,
+        };
+        link.send(Packet::new(STATUS, STATUS_RUNNING, 0, 0));
+        for packet in early {
+            link.handle(packet);
+        }
+
+        Ok(link)
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if self.connected && self.stream.write_all(&packet.to_bytes()).is_err() {
+            self.connected = false;
+        }
+    }
+
+    fn send_timestamp(&mut self) {
+        let timestamp = self.clock;
+        self.send(Packet::new(SYNC3, 0, 0, timestamp));
+    }
+
+    /// Turns a timestamp of the other side into ours.
+    fn local_time(&mut self, timestamp: u32) -> u32 {
+        let local = timestamp.wrapping_sub(self.offset) & TIMESTAMP_MASK;
+        if ahead(local, self.remote_clock) > 0 {
+            self.remote_clock = local;
+        }
+        local
+    }
+
+    fn handle(&mut self, packet: Packet) {
+        match packet.command {
+            SYNC1 => {
+                let time = self.local_time(packet.timestamp);
+                self.pending = Some((packet.b2, time));
+                self.answer_pending(false);
+            }
+            SYNC3 if packet.b2 == 0 => {
+                self.local_time(packet.timestamp);
+            }
+            WANT_DISCONNECT => self.connected = false,
+            // Nothing to do with the joypad and the status of the other
+            // side, answers out of a transfer are stale.
+            _ => {}
+        }
+    }
+
+    /// Answers the transfer of the other side once we got to its time, or
+    /// right away when `now`.
+    fn answer_pending(&mut self, now: bool) {
+        let (byte, time) = match self.pending {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        if !now && ahead(time, self.clock) > 0 {
+            return;
+        }
+        self.pending = None;
+
+        match self.waiting.take() {
+            Some(sent) => {
+                self.received = Some(byte);
+                self.send(Packet::new(SYNC2, sent, 0x80, 0));
+            }
+            None => self.send(Packet::new(SYNC3, 1, 0, 0)),
+        }
+    }
+
+    /// Waits for the next packet, `None` if the other side is gone.
+    fn next_packet(&mut self) -> Option<Packet> {
+        if !self.connected {
+            return None;
+        }
+
+        match self.packets.recv() {
+            Ok(packet) => Some(packet),
+            Err(_) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+}
+
+impl LinkPort for BgbLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let timestamp = self.clock;
+        self.send(Packet::new(SYNC1, byte, 0x81, timestamp));
+
+        while let Some(packet) = self.next_packet() {
+            match packet.command {
+                SYNC2 => return packet.b2,
+                SYNC3 if packet.b2 == 1 => return DISCONNECTED,
+                _ => self.handle(packet),
+            }
+            // The other side won't run until it gets its answer
+            self.answer_pending(true);
+        }
+
+        DISCONNECTED
+    }
+
+    fn set_waiting(&mut self, byte: Option<u8>) {
+        self.waiting = byte;
+        if byte.is_none() {
+            self.received = None;
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.received.take()
+    }
+
+    fn cpu_step(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        self.clock = (self.clock + 1) & TIMESTAMP_MASK;
+
+        loop {
+            match self.packets.try_recv() {
+                Ok(packet) => self.handle(packet),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.connected = false;
+                    return;
+                }
+            }
+        }
+        self.answer_pending(false);
+
+        if self.clock & (SYNC_INTERVAL - 1) == 0 {
+            self.send_timestamp();
+        }
+
+        if ahead(self.clock, self.remote_clock) > MAX_LEAD {
+            // Tell where we are, the other side might be waiting for us too
+            self.send_timestamp();
+            while ahead(self.clock, self.remote_clock) > MAX_LEAD {
+                match self.next_packet() {
+                    Some(packet) => self.handle(packet),
+                    None => return,
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BgbLink {
+    fn drop(&mut self) {
+        self.send(Packet::new(WANT_DISCONNECT, 0, 0, 0));
+        // The reader thread holds the socket open otherwise
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use emulator::Emulator;
+    use link::serial_test_rom;
+    use link::test::DONE;
+
+    /// Runs the test ROM until it's done, returns what it received.
+    fn run(link: BgbLink, master: bool) -> Vec<u8> {
+        let rom = serial_test_rom(if master { 1 } else { 0 });
+        let mut emulator = Emulator::from_data(&rom, 44100.0).unwrap();
+        emulator.set_link_port(Some(Box::new(link)));
+
+        for _ in 0..1000000 {
+            if emulator.cpu.get_PC() == DONE {
+                break;
+            }
+            emulator.cpu.next_instruction();
+        }
+
+        (0..8).map(|i| emulator.cpu.deref(0xC100 + i)).collect()
+    }
+
+    #[test]
+    fn loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let slave = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            run(BgbLink::new(stream).unwrap(), false)
+        });
+        let master = run(BgbLink::connect(&address.to_string()).unwrap(), true);
+
+        assert_eq!(master, (0..8).collect::<Vec<u8>>());
+        assert_eq!(slave.join().unwrap(), (0x10..0x18).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(ahead(5, 3), 2);
+        assert_eq!(ahead(3, 5), -2);
+        // Across the wrap around
+        assert_eq!(ahead(1, TIMESTAMP_MASK), 2);
+        assert_eq!(ahead(TIMESTAMP_MASK, 1), -2);
+    }
+}
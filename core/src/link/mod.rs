@@ -0,0 +1,188 @@
+use emulator::Emulator;
+use hardware::serial::{LinkPort, DISCONNECTED};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+mod bgb;
+
+pub use self::bgb::BgbLink;
+
+struct Side {
+    /// The byte this side is ready to send with the other side's clock.
+    waiting: Option<u8>,
+    /// What the other side sent with its clock.
+    received: Option<u8>,
+}
+
+/// One end of a cable between two emulators in the same process.
+pub struct CableEnd {
+    sides: Rc<RefCell<[Side; 2]>>,
+    index: usize,
+}
+
+impl CableEnd {
+    /// Both ends of a new cable.
+    pub fn pair() -> (CableEnd, CableEnd) {
+        let side = || Side {
+            waiting: None,
+            received: None,
+        };
+        let sides = Rc::new(RefCell::new([side(), side()]));
+
+        (
+            CableEnd {
+                sides: sides.clone(),
+                index: 0,
+            },
+            CableEnd { sides, index: 1 },
+        )
+    }
+}
+
+impl LinkPort for CableEnd {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let other = &mut self.sides.borrow_mut()[1 - self.index];
+        match other.waiting.take() {
+            Some(sent) => {
+                other.received = Some(byte);
+                sent
+            }
+            None => DISCONNECTED,
+        }
+    }
+
+    fn set_waiting(&mut self, byte: Option<u8>) {
+        let side = &mut self.sides.borrow_mut()[self.index];
+        side.waiting = byte;
+        if byte.is_none() {
+            side.received = None;
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.sides.borrow_mut()[self.index].received.take()
+    }
+}
+
+/**
+ * Two emulators connected by a link cable, e.g. to trade or to play
+ * against each other.
+ *
+ * They run one instruction at a time, always the one that's behind, so
+ * that each side sees the other react within the same few cycles as on
+ * the real hardware.
+ */
+pub struct LinkCable {
+    pub first: Emulator,
+    pub second: Emulator,
+    /// How many cycles the first emulator is ahead of the second.
+    lead: i64,
+}
+
+impl LinkCable {
+    pub fn new(mut first: Emulator, mut second: Emulator) -> LinkCable {
+        let (first_end, second_end) = CableEnd::pair();
+        first.set_link_port(Some(Box::new(first_end)));
+        second.set_link_port(Some(Box::new(second_end)));
+
+        LinkCable {
+            first,
+            second,
+            lead: 0,
+        }
+    }
+
+    pub fn next_instruction(&mut self) {
+        if self.lead <= 0 {
+            self.lead += run_instruction(&mut self.first);
+        } else {
+            self.lead -= run_instruction(&mut self.second);
+        }
+    }
+
+    /// Unplugs the cable, giving the emulators back.
+    pub fn unplug(mut self) -> (Emulator, Emulator) {
+        self.first.set_link_port(None);
+        self.second.set_link_port(None);
+        (self.first, self.second)
+    }
+}
+
+/// Returns the cycles it took.
+fn run_instruction(emulator: &mut Emulator) -> i64 {
+    let before = emulator.cpu.get_cycles();
+    emulator.cpu.next_instruction();
+    (emulator.cpu.get_cycles() - before) as i64
+}
+
+/// A ROM that sends 8 bytes over the serial port and keeps what comes back
+/// at 0xC100, to test link cables. Bit 0 of `clock` selects the internal
+/// clock, the bytes sent are `clock << 4` and up.
+pub fn serial_test_rom(clock: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // NOP, JP 0x150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+
+    let mut code = vec![];
+    // LD A, clock; LD (0xC000), A
+    code.extend_from_slice(&[0x3E, clock, 0xEA, 0x00, 0xC0]);
+    // LD SP, 0xFFFE; LD HL, 0xC100; LD B, 0
+    code.extend_from_slice(&[0x31, 0xFE, 0xFF, 0x21, 0x00, 0xC1, 0x06, 0x00]);
+    // Give the other side time to get ready: LD C, 0; DEC C; JR NZ
+    code.extend_from_slice(&[0x0E, 0x00, 0x0D, 0x20, 0xFD]);
+    // SB = (0xC000) << 4 + B
+    code.extend_from_slice(&[0xFA, 0x00, 0xC0, 0xCB, 0x37, 0x80, 0xE0, 0x01]);
+    // SC = (0xC000) | 0x80
+    code.extend_from_slice(&[0xFA, 0x00, 0xC0, 0xF6, 0x80, 0xE0, 0x02]);
+    // Wait for SC bit 7 to clear
+    code.extend_from_slice(&[0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA]);
+    // LD A, (SB); LD (HL+), A; INC B
+    code.extend_from_slice(&[0xF0, 0x01, 0x22, 0x04]);
+    // Back to the delay until B is 8, then JR to itself
+    code.extend_from_slice(&[0x78, 0xFE, 0x08, 0x20, 0xDD, 0x18, 0xFE]);
+
+    rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+    rom
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Where the test ROM loops when it's done.
+    pub const DONE: u16 = 0x180;
+
+    #[test]
+    fn two_emulators() {
+        let first = Emulator::from_data(&serial_test_rom(1), 44100.0).unwrap();
+        let second = Emulator::from_data(&serial_test_rom(0), 44100.0).unwrap();
+
+        let mut cable = LinkCable::new(first, second);
+        for _ in 0..100000 {
+            cable.next_instruction();
+        }
+        let (mut first, mut second) = cable.unplug();
+
+        for i in 0..8 {
+            assert_eq!(first.cpu.deref(0xC100 + i), i as u8);
+            assert_eq!(second.cpu.deref(0xC100 + i), 0x10 + i as u8);
+        }
+        // Both got the serial interrupt
+        assert_eq!(first.cpu.deref(0xFF0F) & 0x08, 0x08);
+        assert_eq!(second.cpu.deref(0xFF0F) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn nobody_waiting() {
+        let (mut first, mut second) = CableEnd::pair();
+        assert_eq!(first.exchange(0x12), DISCONNECTED);
+        assert_eq!(second.receive(), None);
+
+        second.set_waiting(Some(0x34));
+        assert_eq!(first.exchange(0x12), 0x34);
+        assert_eq!(second.receive(), Some(0x12));
+        // Only one byte per transfer
+        assert_eq!(first.exchange(0x56), DISCONNECTED);
+    }
+}
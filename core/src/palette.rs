@@ -0,0 +1,502 @@
+use colorization::{ButtonCombo, Colorization};
+use hardware::ppu::{
+    GrayShade, PixelSource, RawPixel, RawScreenBuffer, ScreenBuffer, SCREEN_X, SCREEN_Y,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+pub const GB_POCKET_PALETTE: Colorization = Colorization::monochrome([
+    Rgb {
+        r: 0xE3,
+        g: 0xE6,
+        b: 0xC9,
+    },
+    Rgb {
+        r: 0xC3,
+        g: 0xC4,
+        b: 0xA5,
+    },
+    Rgb {
+        r: 0x8E,
+        g: 0x8B,
+        b: 0x61,
+    },
+    Rgb {
+        r: 0x6C,
+        g: 0x6C,
+        b: 0x4E,
+    },
+]);
+
+pub const DMG_PALETTE: Colorization = Colorization::monochrome([
+    Rgb {
+        r: 0x7F,
+        g: 0x86,
+        b: 0x0F,
+    },
+    Rgb {
+        r: 0x57,
+        g: 0x7C,
+        b: 0x45,
+    },
+    Rgb {
+        r: 0x36,
+        g: 0x5D,
+        b: 0x48,
+    },
+    Rgb {
+        r: 0x2A,
+        g: 0x45,
+        b: 0x3B,
+    },
+]);
+
+pub const GRAYSCALE_PALETTE: Colorization = Colorization::monochrome([
+    Rgb {
+        r: 0xFF,
+        g: 0xFF,
+        b: 0xFF,
+    },
+    Rgb {
+        r: 0xBF,
+        g: 0xBF,
+        b: 0xBF,
+    },
+    Rgb {
+        r: 0x7F,
+        g: 0x7F,
+        b: 0x7F,
+    },
+    Rgb {
+        r: 0x3F,
+        g: 0x3F,
+        b: 0x3F,
+    },
+]);
+
+/// The palette front-ends use unless the user picks a different one.
+pub const DEFAULT_PALETTE: Colorization = GB_POCKET_PALETTE;
+
+/// Looks up a palette by name: "dmg", "gb_pocket", "grayscale" or the name
+/// of one of the CGB button combos.
+pub fn builtin_palette(name: &str) -> Option<Colorization> {
+    match name {
+        "dmg" => Some(DMG_PALETTE),
+        "gb_pocket" => Some(GB_POCKET_PALETTE),
+        "grayscale" => Some(GRAYSCALE_PALETTE),
+        _ => ButtonCombo::from_name(name).map(|c| c.colorization()),
+    }
+}
+
+/// Parses a user palette, either a JASC `.pal` file or a list of hex
+/// colors like `#E3E6C9`, from the lightest to the darkest shade. Four
+/// colors are used for the whole screen, twelve are split between the
+/// background, OBJ0 and OBJ1.
+pub fn parse_palette(text: &str) -> Result<Colorization, String> {
+    let colors = if text.trim_start().starts_with("JASC-PAL") {
+        parse_jasc(text)?
+    } else {
+        parse_hex_list(text)?
+    };
+
+    let palette = |i: usize| [colors[i], colors[i + 1], colors[i + 2], colors[i + 3]];
+    match colors.len() {
+        4 => Ok(Colorization::monochrome(palette(0))),
+        12 => Ok(Colorization {
+            background: palette(0),
+            obj0: palette(4),
+            obj1: palette(8),
+        }),
+        n => Err(format!("Palettes need 4 or 12 colors, found {}.", n)),
+    }
+}
+
+fn parse_jasc(text: &str) -> Result<Vec<Rgb>, String> {
+    let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+
+    // Header, version and number of colors
+    lines.next();
+    lines.next();
+    let count = lines
+        .next()
+        .and_then(|l| l.parse::<usize>().ok())
+        .ok_or("Missing number of colors in JASC palette.".to_string())?;
+
+    let colors = lines
+        .take(count)
+        .map(|line| {
+            let channels: Vec<u8> = line
+                .split_whitespace()
+                .map(|c| c.parse::<u8>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("Invalid color '{}'.", line))?;
+            match channels[..] {
+                [r, g, b] => Ok(Rgb { r, g, b }),
+                _ => Err(format!("Invalid color '{}'.", line)),
+            }
+        })
+        .collect::<Result<Vec<Rgb>, String>>()?;
+
+    if colors.len() != count {
+        return Err(format!(
+            "Expected {} colors in JASC palette, found {}.",
+            count,
+            colors.len()
+        ));
+    }
+
+    Ok(colors)
+}
+
+fn parse_hex_list(text: &str) -> Result<Vec<Rgb>, String> {
+    text.lines()
+        // Everything after ';' is a comment
+        .map(|l| l.split(';').next().unwrap())
+        .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            let hex = c.trim_start_matches('#').trim_start_matches("0x");
+            if hex.len() != 6 {
+                return Err(format!("Invalid color '{}'.", c));
+            }
+            u32::from_str_radix(hex, 16)
+                .map(|v| Rgb {
+                    r: (v >> 16) as u8,
+                    g: (v >> 8) as u8,
+                    b: v as u8,
+                })
+                .map_err(|_| format!("Invalid color '{}'.", c))
+        })
+        .collect()
+}
+
+/// Layout of the pixels written by `FrameConverter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// One byte per channel in R, G, B, A order.
+    Rgba8888,
+    /// 16-bit words in native byte order.
+    Rgb565,
+    /// 32-bit words in native byte order, alpha in the top byte.
+    Argb8888,
+}
+
+impl FrameFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            FrameFormat::Rgb565 => 2,
+            FrameFormat::Rgba8888 | FrameFormat::Argb8888 => 4,
+        }
+    }
+
+    pub fn frame_size(self) -> usize {
+        SCREEN_X * SCREEN_Y * self.bytes_per_pixel()
+    }
+
+    fn pack(self, c: Rgb) -> u32 {
+        let (r, g, b) = (c.r as u32, c.g as u32, c.b as u32);
+        match self {
+            FrameFormat::Rgba8888 => u32::from_be_bytes([c.r, c.g, c.b, 0xFF]),
+            FrameFormat::Rgb565 => ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3),
+            FrameFormat::Argb8888 => 0xFF00_0000 | (r << 16) | (g << 8) | b,
+        }
+    }
+
+    #[inline]
+    fn write(self, color: u32, out: &mut [u8]) {
+        match self {
+            FrameFormat::Rgba8888 => out.copy_from_slice(&color.to_be_bytes()),
+            FrameFormat::Rgb565 => out.copy_from_slice(&(color as u16).to_ne_bytes()),
+            FrameFormat::Argb8888 => out.copy_from_slice(&color.to_ne_bytes()),
+        }
+    }
+}
+
+/// Index of the color of a pixel in `FrameConverter`.
+#[inline]
+fn color_index(pixel: &RawPixel) -> usize {
+    let shade = ((pixel.palette >> (pixel.color * 2)) & 0b11) as usize;
+    let palette = match pixel.source {
+        PixelSource::Background | PixelSource::Window => 0,
+        PixelSource::Sprite0 => 4,
+        PixelSource::Sprite1 => 8,
+    };
+    palette + shade
+}
+
+/// Turns the screen buffers into pixels ready to be uploaded, every color
+/// is computed once when the converter is created.
+pub struct FrameConverter {
+    format: FrameFormat,
+    // Four shades for the background, OBJ0 and OBJ1
+    colors: [u32; 12],
+    rgb: [Rgb; 12],
+}
+
+impl FrameConverter {
+    pub fn new(colorization: &Colorization, format: FrameFormat) -> FrameConverter {
+        let mut colors = [0; 12];
+        let mut rgb = [Rgb { r: 0, g: 0, b: 0 }; 12];
+        let palettes = [
+            &colorization.background,
+            &colorization.obj0,
+            &colorization.obj1,
+        ];
+        for (i, palette) in palettes.iter().enumerate() {
+            for (shade, &c) in palette.iter().enumerate() {
+                colors[i * 4 + shade] = format.pack(c);
+                rgb[i * 4 + shade] = c;
+            }
+        }
+
+        FrameConverter {
+            format,
+            colors,
+            rgb,
+        }
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    /// Converts a monochrome screen, only the background palette is used.
+    pub fn convert(&self, screen: &ScreenBuffer, out: &mut [u8]) {
+        let size = self.format.bytes_per_pixel();
+        assert!(out.len() >= self.format.frame_size());
+
+        for (pixel, out) in screen
+            .iter()
+            .flat_map(|row| row.iter())
+            .zip(out.chunks_mut(size))
+        {
+            let shade = match *pixel {
+                GrayShade::Transparent => 0,
+                shade => shade as usize,
+            };
+            self.format.write(self.colors[shade], out);
+        }
+    }
+
+    /// Converts a screen using a different palette for the background
+    /// and each of the sprite palettes.
+    pub fn convert_raw(&self, screen: &RawScreenBuffer, out: &mut [u8]) {
+        let size = self.format.bytes_per_pixel();
+        assert!(out.len() >= self.format.frame_size());
+
+        for (pixel, out) in screen
+            .iter()
+            .flat_map(|row| row.iter())
+            .zip(out.chunks_mut(size))
+        {
+            self.format.write(self.colors[color_index(pixel)], out);
+        }
+    }
+}
+
+/// How quickly the LCD follows the picture, as the fraction of the way to
+/// the new color a pixel covers every frame. The DMG LCD takes longer to
+/// lighten up than to darken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LcdResponse {
+    pub darken: f32,
+    pub lighten: f32,
+}
+
+impl LcdResponse {
+    /// From 0, an LCD that shows every frame as is, to 1, one so slow that
+    /// sprites drawn every other frame look half transparent.
+    pub fn with_ghosting(amount: f32) -> LcdResponse {
+        let amount = amount.clamp(0.0, 1.0);
+        LcdResponse {
+            darken: 1.0 - amount * 0.5,
+            lighten: 1.0 - amount * 0.75,
+        }
+    }
+}
+
+/// Emulates the ghosting of the DMG LCD by blending every frame with the
+/// ones before it. Needs to see every frame the PPU draws.
+pub struct FrameBlender {
+    response: LcdResponse,
+    pixels: Vec<[f32; 3]>,
+    primed: bool,
+}
+
+impl FrameBlender {
+    pub fn new(response: LcdResponse) -> FrameBlender {
+        FrameBlender {
+            response,
+            pixels: vec![[0.0; 3]; SCREEN_X * SCREEN_Y],
+            primed: false,
+        }
+    }
+
+    pub fn response(&self) -> LcdResponse {
+        self.response
+    }
+
+    pub fn set_response(&mut self, response: LcdResponse) {
+        self.response = response;
+    }
+
+    /// Forgets the previous frames, e.g. after loading a different game.
+    pub fn reset(&mut self) {
+        self.primed = false;
+    }
+
+    /// Blends a new frame in and writes the result like
+    /// `FrameConverter::convert_raw` would.
+    pub fn blend_raw(
+        &mut self,
+        converter: &FrameConverter,
+        screen: &RawScreenBuffer,
+        out: &mut [u8],
+    ) {
+        let format = converter.format;
+        let size = format.bytes_per_pixel();
+        assert!(out.len() >= format.frame_size());
+
+        let response = self.response;
+        let primed = self.primed;
+        for ((pixel, current), out) in screen
+            .iter()
+            .flat_map(|row| row.iter())
+            .zip(self.pixels.iter_mut())
+            .zip(out.chunks_mut(size))
+        {
+            let target = converter.rgb[color_index(pixel)];
+            let target = [target.r as f32, target.g as f32, target.b as f32];
+
+            for (c, &t) in current.iter_mut().zip(target.iter()) {
+                if !primed {
+                    *c = t;
+                } else if t < *c {
+                    *c += (t - *c) * response.darken;
+                } else {
+                    *c += (t - *c) * response.lighten;
+                }
+            }
+
+            let color = Rgb {
+                r: current[0].round() as u8,
+                g: current[1].round() as u8,
+                b: current[2].round() as u8,
+            };
+            format.write(format.pack(color), out);
+        }
+
+        self.primed = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn hex_palette() {
+        let palette = parse_palette("#E3E6C9 C3C4A5\n0x8E8B61, 6C6C4E ; pocket").unwrap();
+        assert_eq!(palette, GB_POCKET_PALETTE);
+
+        assert!(parse_palette("E3E6C9 C3C4A5 8E8B61").is_err());
+        assert!(parse_palette("E3E6C9 C3C4A5 8E8B61 6C6C4Z").is_err());
+    }
+
+    #[test]
+    pub fn jasc_palette() {
+        let mut text = "JASC-PAL\r\n0100\r\n12\r\n".to_string();
+        for i in 0..12 {
+            text += &format!("{} {} {}\r\n", i, i * 2, 255 - i);
+        }
+
+        let palette = parse_palette(&text).unwrap();
+        assert_eq!(palette.background[1], Rgb { r: 1, g: 2, b: 254 });
+        assert_eq!(palette.obj0[0], Rgb { r: 4, g: 8, b: 251 });
+        assert_eq!(
+            palette.obj1[3],
+            Rgb {
+                r: 11,
+                g: 22,
+                b: 244
+            }
+        );
+
+        assert!(parse_palette("JASC-PAL\n0100\n4\n0 0 0\n").is_err());
+    }
+
+    #[test]
+    pub fn frame_formats() {
+        let mut screen = [[RawPixel::blank(); SCREEN_X]; SCREEN_Y];
+        screen[0][1] = RawPixel {
+            color: 1,
+            source: PixelSource::Sprite1,
+            palette: 0b11_10_01_00,
+        };
+
+        let mut colorization = GRAYSCALE_PALETTE;
+        colorization.obj1[1] = Rgb {
+            r: 0x12,
+            g: 0x34,
+            b: 0x56,
+        };
+
+        let mut out = vec![0; FrameFormat::Rgba8888.frame_size()];
+        FrameConverter::new(&colorization, FrameFormat::Rgba8888).convert_raw(&screen, &mut out);
+        assert_eq!(&out[..8], &[0xFF, 0xFF, 0xFF, 0xFF, 0x12, 0x34, 0x56, 0xFF]);
+
+        FrameConverter::new(&colorization, FrameFormat::Argb8888).convert_raw(&screen, &mut out);
+        assert_eq!(&out[4..8], &0xFF12_3456u32.to_ne_bytes());
+
+        let mut out = vec![0; FrameFormat::Rgb565.frame_size()];
+        FrameConverter::new(&colorization, FrameFormat::Rgb565).convert_raw(&screen, &mut out);
+        assert_eq!(&out[..2], &0xFFFFu16.to_ne_bytes());
+        assert_eq!(&out[2..4], &0x11AAu16.to_ne_bytes());
+    }
+
+    #[test]
+    pub fn frame_blending() {
+        let mut screen = [[RawPixel::blank(); SCREEN_X]; SCREEN_Y];
+        let converter = FrameConverter::new(&GRAYSCALE_PALETTE, FrameFormat::Rgba8888);
+        let mut blender = FrameBlender::new(LcdResponse::with_ghosting(1.0));
+        let mut out = vec![0; FrameFormat::Rgba8888.frame_size()];
+
+        // The first frame is shown as is
+        blender.blend_raw(&converter, &screen, &mut out);
+        assert_eq!(&out[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // A sprite drawn every other frame settles halfway through its color
+        // and the background
+        for frame in 0..20 {
+            screen[0][0] = RawPixel {
+                color: 3,
+                source: PixelSource::Sprite0,
+                palette: if frame % 2 == 0 { 0b11_00_00_00 } else { 0 },
+            };
+            blender.blend_raw(&converter, &screen, &mut out);
+        }
+        assert!(out[0] > 0x3F && out[0] < 0xFF);
+        assert_eq!(&out[4..8], &[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // An instant LCD shows the last frame
+        blender.set_response(LcdResponse::with_ghosting(0.0));
+        blender.blend_raw(&converter, &screen, &mut out);
+        assert_eq!(&out[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    pub fn monochrome_frame() {
+        let mut screen = [[GrayShade::C00; SCREEN_X]; SCREEN_Y];
+        screen[SCREEN_Y - 1][SCREEN_X - 1] = GrayShade::C11;
+
+        let mut out = vec![0; FrameFormat::Rgba8888.frame_size()];
+        FrameConverter::new(&GRAYSCALE_PALETTE, FrameFormat::Rgba8888).convert(&screen, &mut out);
+        assert_eq!(&out[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&out[out.len() - 4..], &[0x3F, 0x3F, 0x3F, 0xFF]);
+    }
+}
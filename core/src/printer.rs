@@ -0,0 +1,444 @@
+use colorization::Colorization;
+use hardware::serial::LinkPort;
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const BREAK: u8 = 0x08;
+const STATUS: u8 = 0x0F;
+
+/// What the printer answers after a packet, before the status.
+const ALIVE: u8 = 0x81;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+/// The printer has 8KB of RAM, enough for 9 data packets of 0x280 bytes.
+const BUFFER_SIZE: usize = 0x2000;
+
+/// Prints are 20 tiles, 160 pixels, wide.
+pub const PRINT_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINT_WIDTH / 8;
+const TILE_SIZE: usize = 16;
+
+/// Rows of blank paper fed for every unit of margin.
+const MARGIN_ROWS: usize = 8;
+/// How long the printer is busy for every row printed, in CPU steps.
+const STEPS_PER_ROW: u32 = 2048;
+
+/// Games that leave the palette at 0 get the usual one.
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+/// A sheet of paper that came out of the printer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Printout {
+    pub height: usize,
+    /// `PRINT_WIDTH` shades of gray for each row, 0 is the paper.
+    pub shades: Vec<u8>,
+}
+
+impl Printout {
+    /// One byte per channel in R, G, B, A order, using the background
+    /// palette of `colorization`.
+    pub fn to_rgba(&self, colorization: &Colorization) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.shades.len() * 4);
+        for &shade in self.shades.iter() {
+            let color = colorization.background[shade as usize];
+            data.extend_from_slice(&[color.r, color.g, color.b, 0xFF]);
+        }
+        data
+    }
+}
+
+/// Hands out what the printer prints while the emulator owns it.
+#[derive(Clone)]
+pub struct PrinterOutput {
+    printouts: Rc<RefCell<Vec<Printout>>>,
+}
+
+impl PrinterOutput {
+    /// Every sheet finished since the last call.
+    pub fn take(&self) -> Vec<Printout> {
+        mem::take(&mut *self.printouts.borrow_mut())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Packet,
+    Alive,
+    Status,
+}
+
+/**
+ * The Game Boy Printer, plugged in the link port.
+ *
+ * The game drives the clock and sends packets: the magic bytes, a command,
+ * whether the data is compressed, the length of the data, the data and a
+ * checksum. The printer answers with 0x81 and its status on the two bytes
+ * that follow.
+ *
+ * Image data is collected in bands of 160 pixels by 16 and printed with
+ * the margins asked for, a print that doesn't feed paper after itself
+ * continues on the same sheet with the next one.
+ */
+pub struct Printer {
+    state: State,
+    /// The packet after the magic bytes.
+    packet: Vec<u8>,
+    status: u8,
+    /// CPU steps until the printer is done printing.
+    printing: u32,
+    /// Tile data waiting to be printed.
+    buffer: Vec<u8>,
+    /// Rows printed on the current sheet.
+    sheet: Vec<u8>,
+    output: PrinterOutput,
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer {
+            state: State::Magic(0),
+            packet: vec![],
+            status: 0,
+            printing: 0,
+            buffer: vec![],
+            sheet: vec![],
+            output: PrinterOutput {
+                printouts: Rc::new(RefCell::new(vec![])),
+            },
+        }
+    }
+
+    /// Keep this to get the printouts once the printer is plugged in.
+    pub fn output(&self) -> PrinterOutput {
+        self.output.clone()
+    }
+
+    fn status(&self) -> u8 {
+        if self.printing > 0 {
+            self.status | STATUS_PRINTING
+        } else {
+            self.status
+        }
+    }
+
+    /// Command, compression and the length of the data come first, the
+    /// checksum last.
+    fn packet_size(&self) -> Option<usize> {
+        if self.packet.len() < 4 {
+            return None;
+        }
+
+        let length = self.packet[2] as usize | (self.packet[3] as usize) << 8;
+        Some(4 + length + 2)
+    }
+
+    fn process(&mut self) {
+        let size = self.packet.len();
+        let checksum = self.packet[..size - 2]
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        if checksum != (self.packet[size - 2] as u16 | (self.packet[size - 1] as u16) << 8) {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        let data = if self.packet[1] & 1 == 1 {
+            decompress(&self.packet[4..size - 2])
+        } else {
+            self.packet[4..size - 2].to_vec()
+        };
+
+        match self.packet[0] {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            DATA if data.is_empty() => self.status |= STATUS_IMAGE_FULL,
+            DATA => {
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+                self.status |= STATUS_UNPROCESSED;
+            }
+            PRINT if data.len() >= 4 => self.print(data[0], data[1], data[2]),
+            BREAK => {
+                self.buffer.clear();
+                self.printing = 0;
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+            }
+            // Only asks for the status, which comes with every packet anyway
+            STATUS => {}
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8) {
+        let palette = if palette == 0 {
+            DEFAULT_PALETTE
+        } else {
+            palette
+        };
+
+        let before = (margins >> 4) as usize * MARGIN_ROWS;
+        let after = (margins & 0xF) as usize * MARGIN_ROWS;
+
+        // No sheets just feeds the paper
+        let rows = if sheets > 0 {
+            decode(&self.buffer, palette)
+        } else {
+            vec![]
+        };
+        let printed = rows.len() / PRINT_WIDTH;
+
+        self.sheet
+            .resize(self.sheet.len() + before * PRINT_WIDTH, 0);
+        self.sheet.extend_from_slice(&rows);
+        self.sheet.resize(self.sheet.len() + after * PRINT_WIDTH, 0);
+
+        if after > 0 {
+            let shades = mem::take(&mut self.sheet);
+            self.output.printouts.borrow_mut().push(Printout {
+                height: shades.len() / PRINT_WIDTH,
+                shades,
+            });
+        }
+
+        self.buffer.clear();
+        self.printing = (printed + before + after) as u32 * STEPS_PER_ROW;
+        self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Printer {
+        Printer::new()
+    }
+}
+
+/// Runs start with a byte with bit 7 set, the next byte is repeated that
+/// byte's low bits plus 2 times. Otherwise the low bits plus 1 bytes are
+/// copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+
+        if control & 0x80 != 0 {
+            if i < data.len() {
+                out.resize(out.len() + (control & 0x7F) + 2, data[i]);
+            }
+            i += 1;
+        } else {
+            let end = (i + control + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+/// Turns rows of 20 tiles into shades, `PRINT_WIDTH` per row.
+fn decode(tiles: &[u8], palette: u8) -> Vec<u8> {
+    let tile_rows = tiles.len() / (TILES_PER_ROW * TILE_SIZE);
+    let mut shades = vec![0; tile_rows * 8 * PRINT_WIDTH];
+
+    for (i, tile) in tiles
+        .chunks(TILE_SIZE)
+        .take(tile_rows * TILES_PER_ROW)
+        .enumerate()
+    {
+        let (tile_y, tile_x) = (i / TILES_PER_ROW, i % TILES_PER_ROW);
+        for y in 0..8 {
+            let (low, high) = (tile[y * 2], tile[y * 2 + 1]);
+            for x in 0..8 {
+                let color = ((high >> (7 - x)) & 1) << 1 | ((low >> (7 - x)) & 1);
+                let row = tile_y * 8 + y;
+                shades[row * PRINT_WIDTH + tile_x * 8 + x] = (palette >> (color * 2)) & 0b11;
+            }
+        }
+    }
+
+    shades
+}
+
+impl LinkPort for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        match self.state {
+            State::Magic(i) => {
+                self.state = if byte != MAGIC[i] {
+                    State::Magic(0)
+                } else if i == 0 {
+                    State::Magic(1)
+                } else {
+                    self.packet.clear();
+                    State::Packet
+                };
+                0x00
+            }
+            State::Packet => {
+                self.packet.push(byte);
+                if self.packet_size() == Some(self.packet.len()) {
+                    self.process();
+                    self.state = State::Alive;
+                }
+                0x00
+            }
+            State::Alive => {
+                self.state = State::Status;
+                ALIVE
+            }
+            State::Status => {
+                self.state = State::Magic(0);
+                self.status()
+            }
+        }
+    }
+
+    // The printer never drives the clock
+    fn set_waiting(&mut self, _: Option<u8>) {}
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn cpu_step(&mut self) {
+        self.printing = self.printing.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use palette::GRAYSCALE_PALETTE;
+
+    /// Sends a packet, returns the two bytes answered after it.
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut packet = vec![command, compressed as u8];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+
+        for &byte in MAGIC.iter().chain(packet.iter()) {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        (printer.exchange(0x00), printer.exchange(0x00))
+    }
+
+    /// A band of 20 tiles by 2, every tile row in color 3 but the first
+    /// one in color 1.
+    fn band() -> Vec<u8> {
+        let mut tile = [0xFF; TILE_SIZE];
+        tile[1] = 0x00;
+        tile.iter()
+            .cloned()
+            .cycle()
+            .take(2 * TILES_PER_ROW * TILE_SIZE)
+            .collect()
+    }
+
+    #[test]
+    fn rle() {
+        assert_eq!(
+            decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34, 0x80, 0x00]),
+            vec![0xAA, 0xAA, 0xAA, 0x12, 0x34, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn print() {
+        let mut printer = Printer::new();
+        let output = printer.output();
+
+        assert_eq!(send(&mut printer, INIT, false, &[]), (ALIVE, 0x00));
+        assert_eq!(
+            send(&mut printer, DATA, false, &band()),
+            (ALIVE, STATUS_UNPROCESSED)
+        );
+        // The same band again, compressed
+        let compressed: Vec<u8> = (0..TILES_PER_ROW * 2)
+            .flat_map(|_| [0x00, 0xFF, 0x00, 0x00, 0x8C, 0xFF].to_vec())
+            .collect();
+        send(&mut printer, DATA, true, &compressed);
+        assert_eq!(
+            send(&mut printer, DATA, false, &[]),
+            (ALIVE, STATUS_UNPROCESSED | STATUS_IMAGE_FULL)
+        );
+
+        // One sheet, a unit of margin before and two after, inverted
+        // palette
+        let (_, status) = send(&mut printer, PRINT, false, &[0x01, 0x12, 0x1B, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(
+            send(&mut printer, STATUS, false, &[]),
+            (ALIVE, STATUS_PRINTING)
+        );
+        for _ in 0..(8 + 32 + 16) * STEPS_PER_ROW {
+            printer.cpu_step();
+        }
+        assert_eq!(send(&mut printer, STATUS, false, &[]), (ALIVE, 0x00));
+
+        let printouts = output.take();
+        assert_eq!(printouts.len(), 1);
+        let printout = &printouts[0];
+        assert_eq!(printout.height, 8 + 32 + 16);
+
+        let row = |y: usize| &printout.shades[y * PRINT_WIDTH..(y + 1) * PRINT_WIDTH];
+        assert!(row(0).iter().all(|&s| s == 0));
+        // Color 1 and 3 swapped by the palette
+        for band in 0..4 {
+            assert!(row(8 + band * 8).iter().all(|&s| s == 2));
+            assert!(row(9 + band * 8).iter().all(|&s| s == 0));
+        }
+        assert!(row(8 + 32).iter().all(|&s| s == 0));
+
+        let rgba = printout.to_rgba(&GRAYSCALE_PALETTE);
+        assert_eq!(rgba.len(), PRINT_WIDTH * printout.height * 4);
+        assert_eq!(rgba[3], 0xFF);
+    }
+
+    #[test]
+    fn continued_sheet() {
+        let mut printer = Printer::new();
+        let output = printer.output();
+
+        // No margin after, the sheet goes on with the next print
+        send(&mut printer, DATA, false, &band());
+        send(&mut printer, PRINT, false, &[0x01, 0x10, 0xE4, 0x40]);
+        assert!(output.take().is_empty());
+
+        send(&mut printer, DATA, false, &band());
+        send(&mut printer, PRINT, false, &[0x01, 0x03, 0xE4, 0x40]);
+        let printouts = output.take();
+        assert_eq!(printouts.len(), 1);
+        assert_eq!(printouts[0].height, 8 + 16 + 16 + 24);
+    }
+
+    #[test]
+    fn checksum_error() {
+        let mut printer = Printer::new();
+        for &byte in [0x88, 0x33, INIT, 0x00, 0x00, 0x00, 0x02, 0x00].iter() {
+            printer.exchange(byte);
+        }
+        assert_eq!(printer.exchange(0x00), ALIVE);
+        assert_eq!(printer.exchange(0x00), STATUS_CHECKSUM_ERROR);
+
+        assert_eq!(send(&mut printer, INIT, false, &[]), (ALIVE, 0x00));
+    }
+}
@@ -0,0 +1,226 @@
+use emulator::Emulator;
+use hardware::apu::Channel;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the RIFF header before the samples.
+const HEADER_SIZE: u32 = 44;
+
+/**
+ * Writes interleaved stereo samples to a 16-bit PCM WAV file.
+ *
+ * The sizes in the header are only known at the end, `finish` has to be
+ * called for the file to be readable.
+ */
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    /// Bytes of samples written so far.
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> Result<WavWriter<W>, String> {
+        let channels: u16 = 2;
+        let block_align = channels * 2;
+
+        let mut header = vec![];
+        header.extend_from_slice(b"RIFF");
+        // Filled in by finish
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+
+        out.write_all(&header).map_err(|e| e.to_string())?;
+
+        Ok(WavWriter { out, data_size: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), String> {
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        self.out.write_all(&data).map_err(|e| e.to_string())?;
+        self.data_size += data.len() as u32;
+        Ok(())
+    }
+
+    /** Fills in the sizes in the header, returns the underlying writer. */
+    pub fn finish(mut self) -> Result<W, String> {
+        self.write_size(4, HEADER_SIZE - 8 + self.data_size)?;
+        self.write_size(HEADER_SIZE as u64 - 4, self.data_size)?;
+        self.out.flush().map_err(|e| e.to_string())?;
+        Ok(self.out)
+    }
+
+    fn write_size(&mut self, offset: u64, size: u32) -> Result<(), String> {
+        self.out
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.out.write_all(&size.to_le_bytes()))
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn create_wav(path: &Path, sample_rate: u32) -> Result<WavWriter<BufWriter<File>>, String> {
+    let file =
+        File::create(path).map_err(|e| format!("Could not create '{}': {}", path.display(), e))?;
+    WavWriter::new(BufWriter::new(file), sample_rate)
+}
+
+/**
+ * Records the audio of an `Emulator` to WAV files, the mix and optionally
+ * each channel on its own.
+ *
+ * The mix is passed in by the caller, which is usually playing it too,
+ * while the channels are drained from the emulator directly. The files have
+ * the sample rate of the emulator, the rate adjustment has to stay at 1 for
+ * them to play at the right speed.
+ */
+pub struct AudioRecorder {
+    mix: WavWriter<BufWriter<File>>,
+    channels: Vec<(Channel, WavWriter<BufWriter<File>>)>,
+    buffer: Vec<i16>,
+}
+
+impl AudioRecorder {
+    /// With `per_channel` every channel goes to its own file next to
+    /// `path`, e.g. `song.square1.wav` for `song.wav`.
+    pub fn start(
+        emulator: &mut Emulator,
+        path: &Path,
+        per_channel: bool,
+    ) -> Result<AudioRecorder, String> {
+        let sample_rate = emulator.sample_rate().round() as u32;
+        let mix = create_wav(path, sample_rate)?;
+
+        let mut channels = vec![];
+        if per_channel {
+            let stem = path.with_extension("");
+            for &channel in Channel::ALL.iter() {
+                let name = format!("{}.{}.wav", stem.display(), channel.name());
+                channels.push((channel, create_wav(Path::new(&name), sample_rate)?));
+            }
+            emulator.set_channel_buffers(true);
+        }
+
+        Ok(AudioRecorder {
+            mix,
+            channels,
+            buffer: vec![0; 4096],
+        })
+    }
+
+    /** Appends `samples`, the mix as returned by `generate_sound`, and
+     * whatever the channels produced in the meantime. */
+    pub fn record(&mut self, emulator: &mut Emulator, samples: &[i16]) -> Result<(), String> {
+        self.mix.write_samples(samples)?;
+
+        for &mut (channel, ref mut writer) in self.channels.iter_mut() {
+            loop {
+                let count = emulator.generate_channel_sound_into(channel, &mut self.buffer);
+                if count == 0 {
+                    break;
+                }
+                writer.write_samples(&self.buffer[..count])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self, emulator: &mut Emulator) -> Result<(), String> {
+        if !self.channels.is_empty() {
+            emulator.set_channel_buffers(false);
+        }
+
+        self.mix.finish()?;
+        for (_, writer) in self.channels {
+            writer.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hardware::apu::CPU_FREQUENCY;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut writer = WavWriter::new(Cursor::new(vec![]), 44100).unwrap();
+        writer.write_samples(&[1, -1, 0x1234, -0x1234]).unwrap();
+        writer.write_samples(&[7, 8]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4), 36 + 12);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        // PCM, stereo
+        assert_eq!(&data[20..24], &[1, 0, 2, 0]);
+        assert_eq!(u32_at(&data, 24), 44100);
+        assert_eq!(u32_at(&data, 28), 44100 * 4);
+        // 4 bytes per frame, 16 bits per sample
+        assert_eq!(&data[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(&data, 40), 12);
+        assert_eq!(&data[44..50], &[1, 0, 0xFF, 0xFF, 0x34, 0x12]);
+    }
+
+    fn next_frame(emulator: &mut Emulator) -> Vec<i16> {
+        while !emulator.cpu.handler_holder.should_refresh() {
+            emulator.cpu.next_instruction();
+        }
+        emulator.generate_sound()
+    }
+
+    #[test]
+    fn samples_match_sample_rate() {
+        let mut rom = vec![0; 0x8000];
+        // JR to itself
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::from_data(&rom, 48000.0).unwrap();
+        next_frame(&mut emulator);
+
+        let path = env::temp_dir().join("gb-recorder-test.wav");
+        let mut recorder = AudioRecorder::start(&mut emulator, &path, false).unwrap();
+        let frames = 60;
+        for _ in 0..frames {
+            let samples = next_frame(&mut emulator);
+            recorder.record(&mut emulator, &samples).unwrap();
+        }
+        recorder.finish(&mut emulator).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let rate = u32_at(&data, 24);
+        assert_eq!(rate, 48000);
+
+        // 4 bytes per stereo sample, a frame is 70224 cycles
+        let recorded = u32_at(&data, 40) as f64 / 4.0;
+        let expected = (frames * 70224) as f64 * rate as f64 / CPU_FREQUENCY;
+        assert!((recorded - expected).abs() < 16.0);
+    }
+}
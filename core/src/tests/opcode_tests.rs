@@ -1,7 +1,8 @@
 use hardware::apu::*;
 use hardware::cpu::{Cpu, Handler, HandlerHolder, Interrupt, MapperHolder};
 use hardware::opcodes::OpCode;
-use hardware::ppu::{GrayShade, ScreenBuffer};
+use hardware::ppu::{GrayShade, RawPixel, RawScreenBuffer, RenderOptions, ScreenBuffer};
+use hardware::serial::LinkPort;
 
 use hardware::handler_holder::Key;
 
@@ -57,6 +58,7 @@ const MOCK_CHANNEL: &'static MockChannel = &MockChannel {};
 struct MockHandlerHolder {
     memory: [u8; 512],
     screen_buffer: ScreenBuffer,
+    raw_screen_buffer: RawScreenBuffer,
     audio_buffer: MockAudioBuffer,
     data: [u8; 1],
 }
@@ -83,6 +85,7 @@ impl MockHandlerHolder {
         MockHandlerHolder {
             memory: [0; 512],
             screen_buffer: [[GrayShade::C00; 160]; 144],
+            raw_screen_buffer: [[RawPixel::blank(); 160]; 144],
             audio_buffer: MockAudioBuffer {},
             data: [0],
         }
@@ -119,12 +122,38 @@ impl HandlerHolder for MockHandlerHolder {
     fn get_screen_buffer(&self) -> &ScreenBuffer {
         &self.screen_buffer
     }
+    fn get_raw_screen_buffer(&self) -> &RawScreenBuffer {
+        &self.raw_screen_buffer
+    }
     fn should_refresh(&mut self) -> bool {
         false
     }
     fn get_audio_buffer(&self) -> &dyn AudioBuffer {
         &self.audio_buffer
     }
+    fn sample_rate(&self) -> f64 {
+        44100.0
+    }
+    fn set_sample_rate(&mut self, _: f64) {}
+    fn set_rate_adjustment(&mut self, _: f64) {}
+    fn pending_audio_samples(&mut self) -> usize {
+        0
+    }
+    fn drain_audio_samples(&mut self, _: &mut [i16]) -> usize {
+        0
+    }
+    fn drain_channel_samples(&mut self, _: Channel, _: &mut [i16]) -> usize {
+        0
+    }
+    fn mix_options(&self) -> MixOptions {
+        MixOptions::new()
+    }
+    fn set_mix_options(&mut self, _: MixOptions) {}
+    fn set_link_port(&mut self, _: Option<Box<dyn LinkPort>>) {}
+    fn start_vgm_log(&mut self) {}
+    fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        None
+    }
     fn reset(&mut self) {}
     fn ram(&mut self) -> &mut [u8] {
         &mut self.data
@@ -132,6 +161,11 @@ impl HandlerHolder for MockHandlerHolder {
     fn rtc(&mut self) -> Option<&mut u64> {
         None
     }
+    fn set_lenient_access(&mut self, _: bool) {}
+    fn render_options(&self) -> RenderOptions {
+        RenderOptions::new()
+    }
+    fn set_render_options(&mut self, _: RenderOptions) {}
 }
 
 fn reset_all_registers(cpu: &mut Cpu) {
@@ -183,6 +217,39 @@ fn test_jr_n_forward() {
     assert_eq!(cpu.get_PC(), 0x0005);
 }
 
+#[test]
+/** HALT with interrupts disabled and one already pending doesn't halt,
+ * the byte after it is executed twice instead. */
+fn test_halt_bug() {
+    let mut handler = MockHandlerHolder::new();
+    // DI
+    handler.memory[0] = 0xF3;
+    // HALT
+    handler.memory[1] = 0x76;
+    // INC A
+    handler.memory[2] = 0x3C;
+
+    let mut cpu = Cpu::new(Box::new(handler));
+    reset_all_registers(&mut cpu);
+
+    cpu.next_instruction();
+
+    // Enable and request the timer interrupt
+    cpu.set_deref_debug(0xFFFF, 0x04);
+    cpu.request_interrupt(Interrupt::Timer);
+
+    cpu.next_instruction();
+    assert_eq!(cpu.get_PC(), 0x0002);
+
+    cpu.next_instruction();
+    assert_eq!(cpu.get_A_reg(), 0x01);
+    assert_eq!(cpu.get_PC(), 0x0002);
+
+    cpu.next_instruction();
+    assert_eq!(cpu.get_A_reg(), 0x02);
+    assert_eq!(cpu.get_PC(), 0x0003);
+}
+
 #[test]
 fn test_jr_n_backwards() {
     let mut handler = MockHandlerHolder::new();
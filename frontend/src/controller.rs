@@ -3,7 +3,7 @@ use glium::glutin::dpi::LogicalSize;
 use glium::glutin::{ContextBuilder, ElementState, EventsLoop, VirtualKeyCode};
 use sound::SDLPlayer;
 
-use gb::{Emulator, Hardware, Interrupt, Key};
+use gb::{Channel, Colorization, Emulator, Hardware, Interrupt, Key, Layer, LcdResponse};
 
 use gpu::renderer::GLRenderer;
 
@@ -22,6 +22,7 @@ pub enum Event {
     Quit,
     Break,
     ToggleSpeed,
+    ToggleRecording,
     Continue,
 }
 
@@ -43,19 +44,38 @@ impl Controller {
         }
     }
 
-    pub fn refresh(&mut self, emulator: &mut Emulator) {
+    pub fn set_colorization(&mut self, colorization: &Colorization) {
+        self.renderer.set_colorization(colorization);
+    }
+
+    pub fn set_lcd_response(&mut self, response: Option<LcdResponse>) {
+        self.renderer.set_lcd_response(response);
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.player.sample_rate()
+    }
+
+    pub fn refresh(&mut self, emulator: &mut Emulator, samples: &[i16]) {
         {
-            let pixels = emulator.cpu.handler_holder.get_screen_buffer();
+            let pixels = emulator.cpu.handler_holder.get_raw_screen_buffer();
 
             let mut frame = self.display.draw();
             self.renderer.refresh(&mut frame, pixels);
             frame.finish().unwrap();
         }
 
-        {
-            let sound_buffer = emulator.cpu.handler_holder.get_audio_buffer();
-            self.player.refresh(sound_buffer);
-        }
+        self.player.refresh(emulator, samples);
+    }
+
+    fn toggle_layer(emulator: &mut Emulator, layer: Layer) {
+        let visible = emulator.layer_visible(layer);
+        emulator.set_layer_visible(layer, !visible);
+    }
+
+    fn toggle_channel(emulator: &mut Emulator, channel: Channel) {
+        let muted = emulator.channel_muted(channel);
+        emulator.set_channel_muted(channel, !muted);
     }
 
     fn handle_event(event: glutin::Event, emulator: &mut Emulator) -> Event {
@@ -77,6 +97,34 @@ impl Controller {
                             Some(VirtualKeyCode::F2) => {
                                 return Event::ToggleSpeed;
                             }
+                            Some(VirtualKeyCode::F3) => {
+                                Self::toggle_layer(emulator, Layer::Background);
+                            }
+                            Some(VirtualKeyCode::F4) => {
+                                Self::toggle_layer(emulator, Layer::Window);
+                            }
+                            Some(VirtualKeyCode::F5) => {
+                                Self::toggle_layer(emulator, Layer::Sprites);
+                            }
+                            Some(VirtualKeyCode::F6) => {
+                                let enabled = emulator.sprite_boxes();
+                                emulator.set_sprite_boxes(!enabled);
+                            }
+                            Some(VirtualKeyCode::F7) => {
+                                Self::toggle_channel(emulator, Channel::Square1);
+                            }
+                            Some(VirtualKeyCode::F8) => {
+                                Self::toggle_channel(emulator, Channel::Square2);
+                            }
+                            Some(VirtualKeyCode::F9) => {
+                                Self::toggle_channel(emulator, Channel::Wave);
+                            }
+                            Some(VirtualKeyCode::F10) => {
+                                Self::toggle_channel(emulator, Channel::Noise);
+                            }
+                            Some(VirtualKeyCode::F11) => {
+                                return Event::ToggleRecording;
+                            }
                             _ => {}
                         }
                     }
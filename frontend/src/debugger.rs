@@ -3,7 +3,7 @@ use std::io;
 use std::io::Write;
 use std::process;
 
-use gb::{Emulator, OpCode};
+use gb::{Emulator, Layer, OpCode};
 
 pub struct Debugger {
     stepping: bool,
@@ -78,6 +78,8 @@ fn print_help() {
     println!("[c]ontinue       -- continue execution");
     println!("[s]tep           -- go to next instruction");
     println!("d                -- continue execution but print cpu information");
+    println!("layer [layer]    -- show or hide [layer], one of bg, win or obj");
+    println!("boxes            -- show or hide the sprite outlines");
     println!("[q]uit           -- quit application");
     println!("");
 }
@@ -90,6 +92,15 @@ fn to_address(arg: &str) -> Result<u16, ()> {
     u16::from_str_radix(arg, 16).map_err(|_| ())
 }
 
+fn to_layer(arg: &str) -> Result<Layer, ()> {
+    match arg {
+        "bg" => Ok(Layer::Background),
+        "win" => Ok(Layer::Window),
+        "obj" => Ok(Layer::Sprites),
+        _ => Err(()),
+    }
+}
+
 impl Debugger {
     pub fn new() -> Debugger {
         Debugger {
@@ -141,6 +152,11 @@ impl Debugger {
             "h" | "help" => {
                 print_help();
             }
+            "boxes" => {
+                let enabled = !emulator.sprite_boxes();
+                println!("Sprite outlines {}.", if enabled { "on" } else { "off" });
+                emulator.set_sprite_boxes(enabled);
+            }
             _ => {
                 return Err(());
             }
@@ -195,6 +211,16 @@ impl Debugger {
                 println!("Adding {:04X} to the watch list.", address);
                 self.watches.insert(address);
             }
+            "layer" => {
+                let layer = to_layer(arg)?;
+                let visible = !emulator.layer_visible(layer);
+                println!(
+                    "Layer {:?} {}.",
+                    layer,
+                    if visible { "shown" } else { "hidden" }
+                );
+                emulator.set_layer_visible(layer, visible);
+            }
             _ => {
                 return Err(());
             }
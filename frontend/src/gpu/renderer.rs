@@ -7,7 +7,10 @@ use glium::texture::{MipmapsOption, UncompressedFloatFormat};
 use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
 use glium::{DrawParameters, IndexBuffer, Surface, VertexBuffer};
 
-use gb::ScreenBuffer;
+use gb::{
+    Colorization, FrameBlender, FrameConverter, FrameFormat, LcdResponse, RawScreenBuffer,
+    ScreenBuffer,
+};
 
 const TEXTURE_WIDTH: u32 = 256;
 const TEXTURE_HEIGHT: u32 = 256;
@@ -19,13 +22,14 @@ pub trait Renderer {
 }
 
 pub struct GLRenderer {
-    buffer: PixelBuffer<u8>,
+    buffer: PixelBuffer<(u8, u8, u8, u8)>,
     texture: Texture2d,
     vertex_buffer: VertexBuffer<Vertex>,
     index_buffer: IndexBuffer<u16>,
     program: glium::Program,
     matrix: [[f32; 4]; 4],
-    palette: [[f32; 4]; 4],
+    converter: FrameConverter,
+    blender: Option<FrameBlender>,
 }
 
 #[derive(Copy, Clone)]
@@ -83,14 +87,12 @@ impl GLRenderer {
             #version 140
 
             uniform sampler2D tex;
-            uniform mat4 palette;
 
             in vec2 v_tex_coords;
             out vec4 f_color;
 
             void main() {
-              float color = texture(tex, v_tex_coords).x;
-              f_color = palette[uint(color * 255 + 0.5)] / 256;
+              f_color = texture(tex, v_tex_coords);
             }
         "#;
 
@@ -102,7 +104,7 @@ impl GLRenderer {
 
         let texture = Texture2d::empty_with_format(
             display,
-            UncompressedFloatFormat::U8,
+            UncompressedFloatFormat::U8U8U8U8,
             MipmapsOption::NoMipmap,
             TEXTURE_WIDTH,
             TEXTURE_HEIGHT,
@@ -116,13 +118,6 @@ impl GLRenderer {
             [0.0, 0.0, 0.0, 1.0],
         ];
 
-        let palette = [
-            [255.0, 247.0, 123.0, 255.0],
-            [181.0, 174.0, 74.0, 255.0],
-            [107.0, 105.0, 49.0, 255.0],
-            [33.0, 32.0, 16.0, 255.0],
-        ];
-
         GLRenderer {
             buffer: pixel_buffer,
             texture: texture,
@@ -130,10 +125,20 @@ impl GLRenderer {
             index_buffer: index_buffer,
             program: program,
             matrix: matrix,
-            palette: palette,
+            converter: FrameConverter::new(&gb::DEFAULT_PALETTE, FrameFormat::Rgba8888),
+            blender: None,
         }
     }
 
+    pub fn set_colorization(&mut self, colorization: &Colorization) {
+        self.converter = FrameConverter::new(colorization, FrameFormat::Rgba8888);
+    }
+
+    /// Blends frames together like the slow DMG LCD does.
+    pub fn set_lcd_response(&mut self, response: Option<LcdResponse>) {
+        self.blender = response.map(FrameBlender::new);
+    }
+
     fn update_pixels(&mut self) {
         self.texture.main_level().raw_upload_from_pixel_buffer(
             self.buffer.as_slice(),
@@ -143,24 +148,24 @@ impl GLRenderer {
         );
     }
 
-    pub fn refresh(&mut self, frame: &mut glium::Frame, pixels: &ScreenBuffer) {
-        let mut pixel_buffer = [0u8; 160 * 144];
-
-        let mut index = 0;
-        for y in 0..144 {
-            for x in 0..160 {
-                pixel_buffer[index] = pixels[y][x] as u8 + 1;
-                index += 1;
-            }
+    pub fn refresh(&mut self, frame: &mut glium::Frame, pixels: &RawScreenBuffer) {
+        let mut frame_data = vec![0u8; FrameFormat::Rgba8888.frame_size()];
+        match self.blender {
+            Some(ref mut blender) => blender.blend_raw(&self.converter, pixels, &mut frame_data),
+            None => self.converter.convert_raw(pixels, &mut frame_data),
         }
 
+        let pixel_buffer: Vec<(u8, u8, u8, u8)> = frame_data
+            .chunks(4)
+            .map(|c| (c[0], c[1], c[2], c[3]))
+            .collect();
+
         self.buffer.write(&pixel_buffer);
 
         self.update_pixels();
 
         let uniforms = uniform! {
             matrix: self.matrix,
-            palette: self.palette,
             texure: self.texture.sampled()
                 .minify_filter(MinifySamplerFilter::Nearest)
                 .magnify_filter(MagnifySamplerFilter::Nearest)
@@ -18,8 +18,12 @@ extern crate gb;
 use image::ImageBuffer;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use gb::{Cpu, Emulator};
+use gb::{
+    AudioRecorder, BgbLink, Colorization, Cpu, Emulator, FrameConverter, FrameFormat, Gbs,
+    LcdResponse, Printer, Printout,
+};
 
 use self::controller::{Controller, Event};
 use self::debugger::Debugger;
@@ -97,7 +101,17 @@ struct Config {
     commands: Vec<String>,
     integ_tests_string_addr: Option<u16>,
     is_debug: bool,
+    is_lenient: bool,
     screenshot_path: Option<String>,
+    palette: Option<String>,
+    ghosting: Option<f32>,
+    record_audio: Option<String>,
+    record_channels: bool,
+    record_vgm: Option<String>,
+    track: Option<u8>,
+    link_listen: Option<String>,
+    link_connect: Option<String>,
+    printer: bool,
 }
 
 impl Config {
@@ -124,11 +138,46 @@ impl Config {
             None
         };
 
+        let ghosting = if let Some(amount) = matches.value_of("ghosting") {
+            let amount = amount
+                .parse::<u32>()
+                .ok()
+                .filter(|&a| a <= 100)
+                .ok_or(format!(
+                    "Could not parse ghosting '{}'. \
+    Please use a number between 0 and 100.",
+                    amount
+                ))?;
+            Some(amount as f32 / 100.0)
+        } else {
+            None
+        };
+
+        let track = if let Some(track) = matches.value_of("track") {
+            Some(
+                track
+                    .parse::<u8>()
+                    .map_err(|_| format!("Could not parse track '{}'.", track))?,
+            )
+        } else {
+            None
+        };
+
         Ok(Config {
             rom_name: matches.value_of("ROM").unwrap().to_string(),
             is_headless: matches.occurrences_of("headless") > 0,
             is_debug: matches.occurrences_of("debug") > 0,
+            is_lenient: matches.occurrences_of("lenient") > 0,
             screenshot_path: matches.value_of("screenshot").map(|s| s.to_string()),
+            palette: matches.value_of("palette").map(|s| s.to_string()),
+            ghosting: ghosting,
+            record_audio: matches.value_of("record_audio").map(|s| s.to_string()),
+            record_channels: matches.occurrences_of("record_channels") > 0,
+            record_vgm: matches.value_of("record_vgm").map(|s| s.to_string()),
+            track: track,
+            link_listen: matches.value_of("link_listen").map(|s| s.to_string()),
+            link_connect: matches.value_of("link_connect").map(|s| s.to_string()),
+            printer: matches.occurrences_of("printer") > 0,
             timeout: timeout,
             mag: mag,
             commands: commands,
@@ -137,21 +186,94 @@ impl Config {
     }
 }
 
-fn save_screenshot(path: &str, screen: &gb::ScreenBuffer) -> Result<(), String> {
-    let mut img = ImageBuffer::new(gb::SCREEN_X as u32, gb::SCREEN_Y as u32);
-    for i in 0..gb::SCREEN_X {
-        for j in 0..gb::SCREEN_Y {
-            img.put_pixel(
-                i as u32,
-                j as u32,
-                image::Luma([255 - screen[j][i] as u8 * 64]),
-            );
-        }
+/// Palettes can be one of the built-in ones, "auto" or a palette file.
+fn load_palette(name: &str, emulator: &Emulator) -> Result<Colorization, String> {
+    if name == "auto" {
+        return Ok(emulator.colorization());
+    }
+
+    if let Some(palette) = gb::builtin_palette(name) {
+        return Ok(palette);
     }
 
+    let mut text = String::new();
+    File::open(name)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|_| format!("Error: palette '{}' not found.", name))?;
+    gb::parse_palette(&text)
+}
+
+fn save_screenshot(
+    path: &str,
+    screen: &gb::RawScreenBuffer,
+    colorization: &Colorization,
+) -> Result<(), String> {
+    let mut data = vec![0; FrameFormat::Rgba8888.frame_size()];
+    FrameConverter::new(colorization, FrameFormat::Rgba8888).convert_raw(screen, &mut data);
+
+    let img: ImageBuffer<image::Rgba<u8>, _> =
+        ImageBuffer::from_raw(gb::SCREEN_X as u32, gb::SCREEN_Y as u32, data).unwrap();
+    img.save(path).map_err(|e| e.to_string())
+}
+
+fn save_printout(
+    path: &Path,
+    printout: &Printout,
+    colorization: &Colorization,
+) -> Result<(), String> {
+    let img: ImageBuffer<image::Rgba<u8>, _> = ImageBuffer::from_raw(
+        gb::PRINT_WIDTH as u32,
+        printout.height as u32,
+        printout.to_rgba(colorization),
+    )
+    .unwrap();
     img.save(path).map_err(|e| e.to_string())
 }
 
+/// Plays `track` of a GBS file, or the first song of the file if there's
+/// no track. Returns the track playing too.
+fn load_gbs(data: &[u8], track: Option<u8>) -> Result<(Emulator, u8), String> {
+    let gbs = Gbs::from_data(data)?;
+    let track = track.unwrap_or(gbs.first_song);
+    let emulator = Emulator::from_gbs(&gbs, track, 44100.0)?;
+
+    println!(
+        "{} - {} ({}), track {} of {}.",
+        gbs.title, gbs.author, gbs.copyright, track, gbs.song_count
+    );
+
+    Ok((emulator, track))
+}
+
+/// Picks the first `rom<suffix>-N.<extension>` that doesn't exist yet,
+/// e.g. `rom-1.wav`.
+fn next_free_path(rom_name: &str, suffix: &str, extension: &str) -> PathBuf {
+    let stem = Path::new(rom_name).with_extension("");
+    (1..)
+        .map(|n| PathBuf::from(format!("{}{}-{}.{}", stem.display(), suffix, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn toggle_recording(
+    recorder: Option<AudioRecorder>,
+    emulator: &mut Emulator,
+    config: &Config,
+) -> Result<Option<AudioRecorder>, String> {
+    match recorder {
+        Some(recorder) => {
+            recorder.finish(emulator)?;
+            println!("Recording stopped.");
+            Ok(None)
+        }
+        None => {
+            let path = next_free_path(&config.rom_name, "", "wav");
+            println!("Recording audio to '{}'.", path.display());
+            AudioRecorder::start(emulator, &path, config.record_channels).map(Some)
+        }
+    }
+}
+
 pub fn main() {
     let matches = clap_app!(gbrust =>
         (version: "0.1b")
@@ -161,6 +283,25 @@ pub fn main() {
         (@arg mag: -m --magnification +takes_value "Number of times the screen should be magnified. Default is '3'.")
         (@arg debug: -d --debug "Starts in debug mode.")
         (@arg headless: -H --headless "Run in headless mode. For integration tests.")
+        (@arg lenient: --lenient "Allows access to VRAM and OAM in every PPU mode. Useful to debug homebrew.")
+        (@arg palette: -p --palette +takes_value
+            "Colors of the screen: 'gb_pocket', 'dmg', 'grayscale', 'auto' for the palettes a CGB would pick for the game, a CGB button combo like 'up_a' or a palette file.")
+        (@arg ghosting: -g --ghosting +takes_value
+            "Blends consecutive frames like the slow DMG LCD, from 0 to 100. Makes flickering sprites look transparent.")
+        (@arg record_audio: --("record-audio") +takes_value
+            "Records the audio to the WAV file indicated by the argument, from the start until the end of the run.")
+        (@arg record_channels: --("record-channels")
+            "Also records each sound channel to its own WAV file, e.g. 'song.square1.wav' next to 'song.wav'.")
+        (@arg record_vgm: --("record-vgm") +takes_value
+            "Logs the writes to the sound registers to the VGM file indicated by the argument, for chiptune players.")
+        (@arg track: --track +takes_value
+            "Track to play when the ROM is a GBS file, starting from 1. In headless mode the track is recorded to a WAV file unless --record-audio says otherwise.")
+        (@arg link_listen: --("link-listen") +takes_value
+            "Waits for another emulator to connect a link cable at the address indicated by the argument, e.g. '0.0.0.0:8765'. Speaks the BGB link protocol.")
+        (@arg link_connect: --("link-connect") +takes_value conflicts_with[link_listen]
+            "Connects a link cable to the emulator listening at the address indicated by the argument, e.g. 'localhost:8765'.")
+        (@arg printer: --printer conflicts_with[link_listen link_connect]
+            "Plugs a Game Boy Printer in the link port, every sheet printed is saved to 'rom-print-N.png' with the colors of the palette.")
         (@arg screenshot: -S --screenshot +takes_value
             "Takes a screenshot at the end of the run. The screenshot will be saved in the file indicated by the argument.")
         (@arg timeout: -t --timeout +takes_value "Timeout when running headless, in millions of cycles. Default 100")
@@ -172,7 +313,14 @@ pub fn main() {
 
     let config = bail!(Config::from_clap(matches));
 
-    let mut save_file = bail!(open_save_file(&config.rom_name));
+    let is_gbs = config.rom_name.ends_with(".gbs");
+
+    // GBS files have no save to keep
+    let mut save_file = if is_gbs {
+        None
+    } else {
+        Some(bail!(open_save_file(&config.rom_name)))
+    };
 
     let mut controller = if !config.is_headless {
         Some(Controller::new(
@@ -184,17 +332,42 @@ pub fn main() {
     };
 
     let mut emulator;
+    let mut colorization = gb::DEFAULT_PALETTE;
+    let mut record_audio = config.record_audio.clone();
     {
         let mut rom_bytes = vec![];
         let mut rom = bail!(open_rom(&config.rom_name));
         bail!(rom.read_to_end(&mut rom_bytes));
 
-        emulator = Emulator::from_data(&rom_bytes, 44100.0).unwrap();
+        if is_gbs {
+            let (gbs_emulator, track) = bail!(load_gbs(&rom_bytes, config.track));
+            emulator = gbs_emulator;
+
+            if config.is_headless && record_audio.is_none() {
+                let stem = Path::new(&config.rom_name).with_extension("");
+                record_audio = Some(format!("{}-{}.wav", stem.display(), track));
+            }
+        } else {
+            emulator = Emulator::from_data(&rom_bytes, 44100.0).unwrap();
+        }
+        emulator.set_lenient_access(config.is_lenient);
+
+        if let Some(ref name) = config.palette {
+            colorization = bail!(load_palette(name, &emulator));
+        }
+
+        if let Some(ref mut c) = controller {
+            emulator.set_sample_rate(c.sample_rate());
+            c.set_colorization(&colorization);
+            c.set_lcd_response(config.ghosting.map(LcdResponse::with_ghosting));
+        }
 
         // Load save file in ram
         // we don't care if we can't fill the whole buffer, it just
         // means that we don't have a save file
-        let _ = save_file.read_exact(emulator.cpu.handler_holder.ram());
+        if let Some(ref mut f) = save_file {
+            let _ = f.read_exact(emulator.cpu.handler_holder.ram());
+        }
     }
 
     let mut debugger = Debugger::new();
@@ -206,6 +379,38 @@ pub fn main() {
         debugger.breakpoint(&mut emulator);
     }
 
+    if let Some(ref address) = config.link_listen {
+        println!(
+            "Waiting for the other side of the link cable on '{}'.",
+            address
+        );
+        let link = bail!(BgbLink::listen(address));
+        emulator.set_link_port(Some(Box::new(link)));
+    } else if let Some(ref address) = config.link_connect {
+        let link = bail!(BgbLink::connect(address));
+        emulator.set_link_port(Some(Box::new(link)));
+    }
+
+    let mut printer_output = None;
+    if config.printer {
+        let printer = Printer::new();
+        printer_output = Some(printer.output());
+        emulator.set_link_port(Some(Box::new(printer)));
+    }
+
+    let mut recorder = None;
+    if let Some(ref path) = record_audio {
+        recorder = Some(bail!(AudioRecorder::start(
+            &mut emulator,
+            Path::new(path),
+            config.record_channels
+        )));
+    }
+
+    if config.record_vgm.is_some() {
+        emulator.start_vgm_log();
+    }
+
     let mut natural_speed = true;
     let mut counter = config.timeout * 1000000;
 
@@ -216,18 +421,40 @@ pub fn main() {
 
         emulator.cpu.next_instruction();
 
-        if let Some(ref mut c) = controller {
-            if emulator.cpu.handler_holder.should_refresh() {
+        if emulator.cpu.handler_holder.should_refresh() {
+            let samples = emulator.generate_sound();
+            if let Some(ref mut r) = recorder {
+                bail!(r.record(&mut emulator, &samples));
+            }
+
+            if let Some(ref output) = printer_output {
+                for printout in output.take() {
+                    let path = next_free_path(&config.rom_name, "-print", "png");
+                    bail!(save_printout(&path, &printout, &colorization));
+                    println!("Printed '{}'.", path.display());
+                }
+            }
+
+            if let Some(ref mut c) = controller {
                 match c.check_events(&mut emulator) {
                     Event::Quit => break,
                     Event::Break => {
                         debugger.breakpoint(&mut emulator);
                     }
                     Event::ToggleSpeed => natural_speed = !natural_speed,
+                    Event::ToggleRecording => {
+                        recorder = bail!(toggle_recording(recorder.take(), &mut emulator, &config));
+                    }
                     Event::Continue => {}
                 }
 
-                c.refresh(&mut emulator);
+                c.refresh(&mut emulator, &samples);
+
+                // The WAV file has the nominal sample rate, so don't stretch
+                // the audio to the device while recording
+                if recorder.is_some() {
+                    emulator.set_rate_adjustment(1.0);
+                }
 
                 if natural_speed {
                     let elapsed = now.elapsed().unwrap();
@@ -243,6 +470,19 @@ pub fn main() {
         counter -= 1;
     }
 
+    if let Some(mut r) = recorder {
+        let samples = emulator.generate_sound();
+        bail!(r.record(&mut emulator, &samples));
+        bail!(r.finish(&mut emulator));
+    }
+
+    if let Some(ref path) = config.record_vgm {
+        let vgm = emulator.stop_vgm_log().unwrap();
+        bail!(File::create(path)
+            .and_then(|mut f| f.write_all(&vgm))
+            .map_err(|e| format!("Could not write '{}': {}", path, e)));
+    }
+
     if let Some(addr) = config.integ_tests_string_addr {
         print!("{}", parse_string_at(addr, &mut emulator.cpu));
     }
@@ -250,10 +490,13 @@ pub fn main() {
     if let Some(screenshot) = config.screenshot_path {
         bail!(save_screenshot(
             &screenshot,
-            emulator.cpu.handler_holder.get_screen_buffer()
+            emulator.cpu.handler_holder.get_raw_screen_buffer(),
+            &colorization
         ));
     }
 
-    bail!(save_file.seek(SeekFrom::Start(0)));
-    bail!(save_file.write_all(emulator.cpu.handler_holder.ram()));
+    if let Some(mut f) = save_file {
+        bail!(f.seek(SeekFrom::Start(0)));
+        bail!(f.write_all(emulator.cpu.handler_holder.ram()));
+    }
 }
@@ -1,6 +1,13 @@
+extern crate gb;
+extern crate image;
+
 use std::env;
-use std::process::Command;
+use std::fs;
+use std::net::TcpListener;
+use std::process::{self, Command, Stdio};
 use std::str;
+use std::thread;
+use std::time::Duration;
 
 pub fn bin_dir() -> String {
     let mut path = env::current_exe().unwrap();
@@ -53,6 +60,63 @@ pub fn blargg_test_rom(name: &str, expected: &str, timeout: usize) {
     assert_eq!(str::from_utf8(&output.stdout[..]).unwrap(), expected);
 }
 
+/// Builds a ROM that shows a window with a different tile on every other
+/// row of tiles, then every frame hides it from line 40 to 60 and moves it to
+/// the left edge of the screen from line 100 to 120.
+pub fn window_toggle_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // NOP, JP 0x150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+
+    let mut code = vec![];
+    // LD SP, 0xFFFE; wait for VBlank and turn the LCD off
+    code.extend_from_slice(&[0x31, 0xFE, 0xFF, 0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA]);
+    code.extend_from_slice(&[0xAF, 0xE0, 0x40]);
+    // Clear VRAM: LD HL, 0x8000; LD BC, 0x2000; XOR A; LD (HL+), A;
+    // DEC BC; LD A, B; OR C; JR NZ
+    code.extend_from_slice(&[0x21, 0x00, 0x80, 0x01, 0x00, 0x20]);
+    code.extend_from_slice(&[0xAF, 0x22, 0x0B, 0x78, 0xB1, 0x20, 0xF9]);
+    // Copy tiles 1 and 2 from 0x200: LD DE, 0x200; LD HL, 0x8010; LD B, 32;
+    // LD A, (DE); INC DE; LD (HL+), A; DEC B; JR NZ
+    code.extend_from_slice(&[0x11, 0x00, 0x02, 0x21, 0x10, 0x80, 0x06, 0x20]);
+    code.extend_from_slice(&[0x1A, 0x13, 0x22, 0x05, 0x20, 0xFA]);
+    // Window map at 0x9C00, tile 1 and 2 on alternate rows: LD HL, 0x9C00;
+    // LD C, 32; LD A, C; AND 1; INC A; LD B, 32; LD (HL+), A; DEC B; JR NZ;
+    // DEC C; JR NZ
+    code.extend_from_slice(&[0x21, 0x00, 0x9C, 0x0E, 0x20, 0x79, 0xE6, 0x01]);
+    code.extend_from_slice(&[0x3C, 0x06, 0x20, 0x22, 0x05, 0x20, 0xFC, 0x0D]);
+    code.extend_from_slice(&[0x20, 0xF3]);
+    // BGP = 0xE4, WY = 16, WX = 47
+    code.extend_from_slice(&[0x3E, 0xE4, 0xE0, 0x47, 0x3E, 0x10, 0xE0, 0x4A]);
+    code.extend_from_slice(&[0x3E, 0x2F, 0xE0, 0x4B]);
+    // LCDC = 0xF1: LCD, window with the map at 0x9C00 and BG on
+    code.extend_from_slice(&[0x3E, 0xF1, 0xE0, 0x40]);
+    // Wait for LY = 40 and turn the window off
+    code.extend_from_slice(&[0xF0, 0x44, 0xFE, 0x28, 0x20, 0xFA]);
+    code.extend_from_slice(&[0xF0, 0x40, 0xCB, 0xAF, 0xE0, 0x40]);
+    // Wait for LY = 60 and turn the window back on
+    code.extend_from_slice(&[0xF0, 0x44, 0xFE, 0x3C, 0x20, 0xFA]);
+    code.extend_from_slice(&[0xF0, 0x40, 0xCB, 0xEF, 0xE0, 0x40]);
+    // Wait for LY = 100 and set WX = 7
+    code.extend_from_slice(&[0xF0, 0x44, 0xFE, 0x64, 0x20, 0xFA]);
+    code.extend_from_slice(&[0x3E, 0x07, 0xE0, 0x4B]);
+    // Wait for LY = 120, set WX = 47 and wait for the next frame
+    code.extend_from_slice(&[0xF0, 0x44, 0xFE, 0x78, 0x20, 0xFA]);
+    code.extend_from_slice(&[0x3E, 0x2F, 0xE0, 0x4B, 0x18, 0xD2]);
+
+    rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+
+    // Tile 1 is solid black, tile 2 has lines of every color
+    for i in 0..16 {
+        rom[0x200 + i] = 0xFF;
+    }
+    rom[0x210..0x220].copy_from_slice(&[
+        0x00, 0x00, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0x00, 0x00, 0xFF, 0xFF,
+        0xFF,
+    ]);
+    rom
+}
+
 #[test]
 pub fn blargg_instr_timing() {
     blargg_test_rom("instr_timing", "instr_timing\n\n\nPassed\n", 1);
@@ -80,13 +144,11 @@ pub fn blargg_cpu_instrs() {
 
 #[test]
 pub fn blargg_halt_bug() {
-    // TODO:
     blargg_test_rom_with_address(
         "halt_bug",
         "halt bug\n\nIE IF IF DE\n01 10 F1 0C04 \n01 00 E1 0C04 \n01 \
-01 E1 0C04 \n11 00 E1 0C04 \n11 10 F1 0C04 \n11 11 F1 0C04 \n\
-E1 00 E1 0C04 \nE1 E0 E1 0C04 \nE1 E1 E1 0C04 \n2A6CE34B \n\
-Failed\n",
+01 E1 0411 \n11 00 E1 0C04 \n11 10 F1 0411 \n11 11 F1 0411 \n\
+E1 00 E1 0C04 \nE1 E0 E1 0C04 \nE1 E1 E1 0411 \n\nPassed\n",
         0xA004,
         2,
     );
@@ -94,7 +156,9 @@ Failed\n",
 
 #[test]
 pub fn blargg_interrupt_time() {
-    // TODO:
+    // This is a CGB-only test, the second half runs in double speed mode
+    // which the DMG doesn't have, so it can never pass here. We just check
+    // that the normal speed timings are right.
     blargg_test_rom_with_address(
         "interrupt_time",
         "interrupt time\n\n00 00 00 \n00 08 0D \n00 00 00 \n00 08 0D \n\
@@ -106,12 +170,10 @@ pub fn blargg_interrupt_time() {
 
 #[test]
 pub fn blargg_dmg_sound() {
-    // TODO: Fix test 9, 10, 12 failures
     blargg_test_rom_with_address(
         "dmg_sound",
         "dmg_sound\n\n01:ok  02:ok  03:ok  04:ok  05:ok  06:ok  07:ok  08:ok  \
-09:01  10:01  11:ok  12:01  \n\nRun failed tests\nindividually for\nmore \
-details.\n\nFailed #9\n",
+09:ok  10:ok  11:ok  12:ok  \n\nPassed\n",
         0xA004,
         30,
     );
@@ -146,3 +208,359 @@ pub fn gekkio_acceptance_intr_timing() {
 pub fn gekkio_acceptance_di_timing_gs() {
     gekkio_test_rom("acceptance/di_timing-GS", 1);
 }
+
+#[test]
+pub fn gekkio_acceptance_halt_ime0_ei() {
+    gekkio_test_rom("acceptance/halt_ime0_ei", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_halt_ime0_nointr_timing() {
+    gekkio_test_rom("acceptance/halt_ime0_nointr_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_halt_ime1_timing() {
+    gekkio_test_rom("acceptance/halt_ime1_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_halt_ime1_timing2_gs() {
+    gekkio_test_rom("acceptance/halt_ime1_timing2-GS", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_if_ie_registers() {
+    gekkio_test_rom("acceptance/if_ie_registers", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_rapid_di_ei() {
+    gekkio_test_rom("acceptance/rapid_di_ei", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_reti_intr_timing() {
+    gekkio_test_rom("acceptance/reti_intr_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_div_write() {
+    gekkio_test_rom("acceptance/timer/div_write", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim00() {
+    gekkio_test_rom("acceptance/timer/tim00", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim00_div_trigger() {
+    gekkio_test_rom("acceptance/timer/tim00_div_trigger", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim01() {
+    gekkio_test_rom("acceptance/timer/tim01", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim01_div_trigger() {
+    gekkio_test_rom("acceptance/timer/tim01_div_trigger", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim10() {
+    gekkio_test_rom("acceptance/timer/tim10", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim10_div_trigger() {
+    gekkio_test_rom("acceptance/timer/tim10_div_trigger", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim11() {
+    gekkio_test_rom("acceptance/timer/tim11", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tim11_div_trigger() {
+    gekkio_test_rom("acceptance/timer/tim11_div_trigger", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_rapid_toggle() {
+    gekkio_test_rom("acceptance/timer/rapid_toggle", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tima_reload() {
+    gekkio_test_rom("acceptance/timer/tima_reload", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tima_write_reloading() {
+    gekkio_test_rom("acceptance/timer/tima_write_reloading", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_timer_tma_write_reloading() {
+    gekkio_test_rom("acceptance/timer/tma_write_reloading", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_add_sp_e_timing() {
+    gekkio_test_rom("acceptance/add_sp_e_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_call_cc_timing() {
+    gekkio_test_rom("acceptance/call_cc_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_call_cc_timing2() {
+    gekkio_test_rom("acceptance/call_cc_timing2", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_call_timing() {
+    gekkio_test_rom("acceptance/call_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_call_timing2() {
+    gekkio_test_rom("acceptance/call_timing2", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_jp_cc_timing() {
+    gekkio_test_rom("acceptance/jp_cc_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_jp_timing() {
+    gekkio_test_rom("acceptance/jp_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ld_hl_sp_e_timing() {
+    gekkio_test_rom("acceptance/ld_hl_sp_e_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_pop_timing() {
+    gekkio_test_rom("acceptance/pop_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_push_timing() {
+    gekkio_test_rom("acceptance/push_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ret_cc_timing() {
+    gekkio_test_rom("acceptance/ret_cc_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ret_timing() {
+    gekkio_test_rom("acceptance/ret_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_reti_timing() {
+    gekkio_test_rom("acceptance/reti_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_rst_timing() {
+    gekkio_test_rom("acceptance/rst_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_oam_dma_basic() {
+    gekkio_test_rom("acceptance/oam_dma/basic", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_oam_dma_reg_read() {
+    gekkio_test_rom("acceptance/oam_dma/reg_read", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_oam_dma_restart() {
+    gekkio_test_rom("acceptance/oam_dma_restart", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_oam_dma_start() {
+    gekkio_test_rom("acceptance/oam_dma_start", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_oam_dma_timing() {
+    gekkio_test_rom("acceptance/oam_dma_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_hblank_ly_scx_timing_gs() {
+    gekkio_test_rom("acceptance/ppu/hblank_ly_scx_timing-GS", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_intr_1_2_timing_gs() {
+    gekkio_test_rom("acceptance/ppu/intr_1_2_timing-GS", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_intr_2_0_timing() {
+    gekkio_test_rom("acceptance/ppu/intr_2_0_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_intr_2_mode0_timing() {
+    gekkio_test_rom("acceptance/ppu/intr_2_mode0_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_intr_2_mode0_timing_sprites() {
+    gekkio_test_rom("acceptance/ppu/intr_2_mode0_timing_sprites", 2);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_intr_2_mode3_timing() {
+    gekkio_test_rom("acceptance/ppu/intr_2_mode3_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_stat_irq_blocking() {
+    gekkio_test_rom("acceptance/ppu/stat_irq_blocking", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_vblank_stat_intr_gs() {
+    gekkio_test_rom("acceptance/ppu/vblank_stat_intr-GS", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_intr_2_oam_ok_timing() {
+    gekkio_test_rom("acceptance/ppu/intr_2_oam_ok_timing", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_lcdon_timing_gs() {
+    gekkio_test_rom("acceptance/ppu/lcdon_timing-GS", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_lcdon_write_timing_gs() {
+    gekkio_test_rom("acceptance/ppu/lcdon_write_timing-GS", 1);
+}
+
+#[test]
+pub fn gekkio_acceptance_ppu_stat_lyc_onoff() {
+    gekkio_test_rom("acceptance/ppu/stat_lyc_onoff", 1);
+}
+
+#[test]
+pub fn window_toggle_screenshot() {
+    let dir = env::temp_dir();
+    let test_rom = dir.join(format!("window_toggle_{}.gb", process::id()));
+    let screenshot = dir.join(format!("window_toggle_{}.png", process::id()));
+    fs::write(&test_rom, window_toggle_rom()).unwrap();
+
+    let output = Command::new(bin_dir())
+        .args(&[
+            test_rom.to_str().unwrap(),
+            "--headless",
+            "--timeout",
+            "1",
+            "--palette",
+            "grayscale",
+            "--screenshot",
+            screenshot.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let expected = image::open("tests/window/window_toggle.png").unwrap();
+    let actual = image::open(&screenshot).unwrap();
+    assert!(expected.to_rgba().into_raw() == actual.to_rgba().into_raw());
+}
+
+#[test]
+pub fn link_cable_over_tcp() {
+    let dir = env::temp_dir();
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let port = address.port();
+    let address = address.to_string();
+
+    // Sends "@ABCDEFG" with the external clock
+    let listen_rom = dir.join(format!("link_listen_{}.gb", port));
+    fs::write(&listen_rom, gb::serial_test_rom(0x04)).unwrap();
+    // Sends "PQRSTUVW" with the internal clock
+    let connect_rom = dir.join(format!("link_connect_{}.gb", port));
+    fs::write(&connect_rom, gb::serial_test_rom(0x05)).unwrap();
+
+    let mut listener = Command::new(bin_dir())
+        .args(&[
+            listen_rom.to_str().unwrap(),
+            "--headless",
+            "--timeout",
+            "1",
+            "--link-listen",
+            &address,
+            "--result",
+            "C100",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The other side might not be listening yet
+    let mut connected = None;
+    for _ in 0..50 {
+        let output = Command::new(bin_dir())
+            .args(&[
+                connect_rom.to_str().unwrap(),
+                "--headless",
+                "--timeout",
+                "1",
+                "--link-connect",
+                &address,
+                "--result",
+                "C100",
+            ])
+            .output()
+            .unwrap();
+        if !output.stdout.starts_with(b"Could not connect") {
+            connected = Some(output);
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let connected = match connected {
+        Some(output) => output,
+        None => {
+            listener.kill().unwrap();
+            panic!("Could not connect to '{}'", address);
+        }
+    };
+    let listened = listener.wait_with_output().unwrap();
+
+    assert_eq!(str::from_utf8(&connected.stdout[..]).unwrap(), "@ABCDEFG");
+    assert_eq!(
+        str::from_utf8(&listened.stdout[..]).unwrap(),
+        format!(
+            "Waiting for the other side of the link cable on '{}'.\nPQRSTUVW",
+            address
+        )
+    );
+}
@@ -17,7 +17,10 @@ use self::uuid::Uuid;
 
 use std::ops::{Deref, DerefMut};
 
-use gb::{Emulator, GrayShade, Hardware, Interrupt, Key};
+use gb::{
+    Colorization, Emulator, FrameBlender, FrameConverter, FrameFormat, Hardware, Interrupt, Key,
+    LcdResponse,
+};
 
 use libretro_backend::{
     AudioVideoInfo, CoreInfo, GameData, JoypadButton, LoadGameResult, PixelFormat, Region,
@@ -30,7 +33,8 @@ struct EmulatorWrapper {
     emulator: Option<Emulator>,
     game_data: Option<GameData>,
     frame: [u8; gb::SCREEN_X * gb::SCREEN_Y * 4],
-    palette: Palette,
+    palette: PaletteOption,
+    blender: Option<FrameBlender>,
     init_variables: bool,
 }
 
@@ -46,7 +50,8 @@ impl EmulatorWrapper {
             emulator: None,
             game_data: None,
             frame: [0xFF; gb::SCREEN_X * gb::SCREEN_Y * 4],
-            palette: GB_POCKET_PALETTE,
+            palette: PaletteOption::Fixed(gb::DEFAULT_PALETTE),
+            blender: None,
             init_variables: false,
         }
     }
@@ -72,16 +77,31 @@ impl EmulatorWrapper {
     pub fn update_variables(&mut self, handle: &mut RuntimeHandle) {
         if let Some(palette) = handle.get_variable("palette") {
             self.palette = match palette.as_str() {
-                "dmg" => DMG_PALETTE,
-                "gb_pocket" => GB_POCKET_PALETTE,
-                _ => GB_POCKET_PALETTE,
+                "auto" => PaletteOption::Auto,
+                name => {
+                    PaletteOption::Fixed(gb::builtin_palette(name).unwrap_or(gb::DEFAULT_PALETTE))
+                }
             };
         }
 
+        if let Some(ghosting) = handle.get_variable("ghosting") {
+            self.blender = ghosting
+                .parse::<u32>()
+                .ok()
+                .filter(|&g| g > 0)
+                .map(|g| FrameBlender::new(LcdResponse::with_ghosting(g as f32 / 100.0)));
+        }
+
         self.init_variables = true;
     }
 }
 
+enum PaletteOption {
+    Fixed(Colorization),
+    /// Uses the palettes the CGB boot ROM picks for the game
+    Auto,
+}
+
 impl Deref for EmulatorWrapper {
     type Target = Emulator;
 
@@ -96,97 +116,6 @@ impl DerefMut for EmulatorWrapper {
     }
 }
 
-struct Color {
-    a: u8,
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
-impl Color {
-    #[inline]
-    pub fn write(&self, out: &mut [u8]) {
-        out[0] = self.b;
-        out[1] = self.g;
-        out[2] = self.r;
-        out[3] = self.a;
-    }
-}
-
-struct Palette {
-    c01: Color,
-    c10: Color,
-    c11: Color,
-    c00: Color,
-}
-
-impl Palette {
-    #[inline]
-    fn color(&self, c: GrayShade) -> &Color {
-        match c {
-            GrayShade::C00 => &self.c00,
-            GrayShade::C01 => &self.c01,
-            GrayShade::C10 => &self.c10,
-            GrayShade::C11 => &self.c11,
-            GrayShade::Transparent => unreachable!(),
-        }
-    }
-}
-
-const GB_POCKET_PALETTE: Palette = Palette {
-    c11: Color {
-        a: 0xFF,
-        r: 0x6C,
-        g: 0x6C,
-        b: 0x4E,
-    },
-    c10: Color {
-        a: 0xFF,
-        r: 0x8E,
-        g: 0x8B,
-        b: 0x61,
-    },
-    c01: Color {
-        a: 0xFF,
-        r: 0xC3,
-        g: 0xC4,
-        b: 0xA5,
-    },
-    c00: Color {
-        a: 0xFF,
-        r: 0xE3,
-        g: 0xE6,
-        b: 0xC9,
-    },
-};
-
-const DMG_PALETTE: Palette = Palette {
-    c11: Color {
-        a: 0xFF,
-        r: 0x2A,
-        g: 0x45,
-        b: 0x3B,
-    },
-    c10: Color {
-        a: 0xFF,
-        r: 0x36,
-        g: 0x5D,
-        b: 0x48,
-    },
-    c01: Color {
-        a: 0xFF,
-        r: 0x57,
-        g: 0x7C,
-        b: 0x45,
-    },
-    c00: Color {
-        a: 0xFF,
-        r: 0x7F,
-        g: 0x86,
-        b: 0x0F,
-    },
-};
-
 impl libretro_backend::Core for EmulatorWrapper {
     fn info() -> CoreInfo {
         CoreInfo::new("gb-rust", env!("CARGO_PKG_VERSION"))
@@ -195,7 +124,34 @@ impl libretro_backend::Core for EmulatorWrapper {
     }
 
     fn variables() -> Variables {
-        Variables::new().variable("palette", &["dmg", "gb_pocket"], "Palette")
+        Variables::new()
+            .variable(
+                "palette",
+                &[
+                    "gb_pocket",
+                    "dmg",
+                    "grayscale",
+                    "auto",
+                    "up",
+                    "up_a",
+                    "up_b",
+                    "left",
+                    "left_a",
+                    "left_b",
+                    "down",
+                    "down_a",
+                    "down_b",
+                    "right",
+                    "right_a",
+                    "right_b",
+                ],
+                "Palette",
+            )
+            .variable(
+                "ghosting",
+                &["off", "25", "50", "75", "100"],
+                "LCD ghosting",
+            )
     }
 
     fn save_memory(&mut self) -> Option<&mut [u8]> {
@@ -273,19 +229,18 @@ impl libretro_backend::Core for EmulatorWrapper {
             self.update_variables(handle);
         }
 
-        let mut screen = [[GrayShade::C00; gb::SCREEN_X]; gb::SCREEN_Y];
-        {
-            let buffer = self.cpu.handler_holder.get_screen_buffer();
-            screen.copy_from_slice(&buffer[..]);
-        }
-
-        for i in 0..gb::SCREEN_Y {
-            for j in 0..gb::SCREEN_X {
-                let color = self.palette.color(screen[i][j]);
-
-                let index = i * gb::SCREEN_X * 4 + j * 4;
-                color.write(&mut self.frame[index..index + 4]);
-            }
+        let colorization = match self.palette {
+            PaletteOption::Fixed(colorization) => colorization,
+            PaletteOption::Auto => self.colorization(),
+        };
+
+        // Can't go through Deref here, the frame is borrowed mutably
+        let emulator = self.emulator.as_ref().unwrap();
+        let screen = emulator.cpu.handler_holder.get_raw_screen_buffer();
+        let converter = FrameConverter::new(&colorization, FrameFormat::Argb8888);
+        match self.blender {
+            Some(ref mut blender) => blender.blend_raw(&converter, screen, &mut self.frame),
+            None => converter.convert_raw(screen, &mut self.frame),
         }
 
         handle.upload_video_frame(&self.frame);
@@ -8,7 +8,7 @@ use std::os::raw::{c_char, c_void};
 use std::ptr;
 use std::slice;
 
-use gb::{Emulator, Hardware, Interrupt, Key};
+use gb::{Emulator, FrameConverter, FrameFormat, Hardware, Interrupt, Key};
 
 const KEYS: [Key; 8] = [
     Key::A,
@@ -21,12 +21,12 @@ const KEYS: [Key; 8] = [
     Key::Start,
 ];
 
-fn store_frame(screen: &gb::ScreenBuffer, data: &mut [u8]) {
-    for i in 0..gb::SCREEN_X {
-        for j in 0..gb::SCREEN_Y {
-            data[i * gb::SCREEN_Y + j] = screen[j][i] as u8;
-        }
-    }
+/// Room for a frame of interleaved stereo samples, with some margin for
+/// the rate adjustment and late frames.
+const SOUND_BUFFER_SIZE: usize = 4096;
+
+fn store_frame(screen: &gb::RawScreenBuffer, data: &mut [u8]) {
+    FrameConverter::new(&gb::DEFAULT_PALETTE, FrameFormat::Rgba8888).convert_raw(screen, data);
 }
 
 static mut EMULATOR: Option<Emulator> = None;
@@ -91,26 +91,34 @@ pub extern "C" fn dealloc(ptr: *mut c_void, cap: usize) {
     }
 }
 
+/// Runs a frame, returns how many sound samples were stored.
 #[no_mangle]
-pub unsafe extern "C" fn main_loop() {
+pub unsafe extern "C" fn main_loop() -> usize {
     if EMULATOR.is_none()
         || GAMEPAD.len() == 0
         || SOUND == ptr::null_mut()
         || SCREEN == ptr::null_mut()
         || PREVIOUS_GAMEPAD.is_none()
     {
-        return;
+        return 0;
     }
 
-    let sound = slice::from_raw_parts_mut(SOUND as *mut i16, 1470);
-    let screen = slice::from_raw_parts_mut(SCREEN as *mut u8, gb::SCREEN_X * gb::SCREEN_Y * 3);
+    let sound = slice::from_raw_parts_mut(SOUND as *mut i16, SOUND_BUFFER_SIZE);
+    let screen = slice::from_raw_parts_mut(SCREEN as *mut u8, FrameFormat::Rgba8888.frame_size());
     main_loop_internal(
         EMULATOR.as_mut().unwrap(),
         screen,
         sound,
         GamepadStatus::from_raw(GAMEPAD),
         &mut PREVIOUS_GAMEPAD,
-    );
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn set_rate_adjustment(factor: f64) {
+    if let Some(ref mut emulator) = EMULATOR {
+        emulator.set_rate_adjustment(factor);
+    }
 }
 
 impl GamepadStatus {
@@ -150,7 +158,7 @@ fn main_loop_internal(
     sound: &mut [i16],
     gamepad: GamepadStatus,
     previous_gamepad: &mut Option<GamepadStatus>,
-) {
+) -> usize {
     update_gamepad(emulator, &gamepad, previous_gamepad.as_ref().unwrap());
 
     loop {
@@ -162,9 +170,9 @@ fn main_loop_internal(
 
     previous_gamepad.replace(gamepad);
 
-    store_frame(emulator.cpu.handler_holder.get_screen_buffer(), screen);
+    store_frame(emulator.cpu.handler_holder.get_raw_screen_buffer(), screen);
 
-    emulator.generate_sound_into(sound);
+    emulator.generate_sound_into(sound)
 }
 
 #[no_mangle]
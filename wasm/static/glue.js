@@ -55,6 +55,11 @@ var FPS = 60;
 var SAMPLE_RATE = 44100;
 var AUDIO_FRAMES_PER_SEC = SAMPLE_RATE / FPS;
 var INTERNAL_AUDIO_FRAMES_PER_SEC = 256;
+// Room for one frame of interleaved samples in the emulator, must match
+// SOUND_BUFFER_SIZE in main.rs
+var SOUND_BUFFER_SIZE = 4096;
+// Same as MAX_RATE_ADJUSTMENT in the emulator
+var MAX_RATE_ADJUSTMENT = 0.005;
 var SCREEN_X = 160;
 var SCREEN_Y = 144;
 
@@ -92,14 +97,15 @@ function pollGamepad() {
     return buttons;
 }
 
-function convertSoundToStereoF16(sound) {
+function convertSoundToStereoF16(sound, count) {
+    let frames = count / 2;
     let channels = [
-      new Float32Array(AUDIO_FRAMES_PER_SEC),
-      new Float32Array(AUDIO_FRAMES_PER_SEC)
+      new Float32Array(frames),
+      new Float32Array(frames)
     ];
 
     for (let channel = 0; channel < 2; channel++) {
-        for (let i = 0; i < AUDIO_FRAMES_PER_SEC; i++) {
+        for (let i = 0; i < frames; i++) {
             channels[channel][i] = sound[i * 2 + channel] / 32768;
         }
     }
@@ -108,15 +114,8 @@ function convertSoundToStereoF16(sound) {
 }
 
 function refreshScreen(screen, img) {
-    for (let i = 0; i < SCREEN_X; i++) {
-        for (let j = 0; j < SCREEN_Y; j++) {
-            let color = 255 - screen[i * SCREEN_Y + j] * 64;
-            img[j * SCREEN_X * 4 + i * 4    ] = color;
-            img[j * SCREEN_X * 4 + i * 4 + 1] = color;
-            img[j * SCREEN_X * 4 + i * 4 + 2] = color;
-            img[j * SCREEN_X * 4 + i * 4 + 3] = 255;
-        }
-    }
+    // The emulator already stores the frame as RGBA
+    img.set(screen);
 }
 
 function refreshGamepad(gamepad, keyboard, ui_buttons) {
@@ -140,9 +139,10 @@ function refreshGamepad(gamepad, keyboard, ui_buttons) {
 }
 
 /* ScriptProcessor only supports buffers of power-of-2 length:
- * 256, 512, 1024, 2048, 4096. The emulator runs off of buffers of length
- * 735 (one frame worth of sound data, SAMPLE_RATE / 60 fps). This class
- * allows consumers to play music buffered for arbitrary-sized chunk of data.
+ * 256, 512, 1024, 2048, 4096. The emulator produces about 735 frames at a
+ * time (one frame worth of sound data, SAMPLE_RATE / 60 fps), but the
+ * exact count changes every frame. This class allows consumers to play
+ * music buffered for arbitrary-sized chunk of data.
  */
 class ArbitraryAudioProcessor {
     constructor(bufferSize, internalBufferSize, channels) {
@@ -229,20 +229,32 @@ class ArbitraryAudioProcessor {
         this.running = true;
     }
 
+    /* How far the buffer is from its ideal level, from -1 when it's
+     * empty to 1 when it's twice as full as it should be. About one
+     * frame of data keeps clear of the frame skipping in _refreshAudio. */
+    level() {
+        let level = (this.remaining - this.bufferSize) / this.bufferSize;
+        return Math.max(-1, Math.min(1, level));
+    }
+
     /* Enqueue data to be played.
      *
      * data = [
-     *      Float32Array(bufferSize), // left channel data
-     *      Float32Array(bufferSize)  // right channel data
+     *      Float32Array(length), // left channel data
+     *      Float32Array(length)  // right channel data
      * ]
      */
     pushData(data) {
+        let length = this.buffers[0].length;
         for (let i = 0; i < this.channels; i++) {
-            if (data[i].length != this.bufferSize) {
+            if (data[i].length > length) {
                 console.error("Data buffer size error.");
                 return;
             }
-            this.buffers[i].set(data[i], this.startIndex);
+            // The buffer is cyclic, the data may have to wrap around
+            let head = Math.min(data[i].length, length - this.startIndex);
+            this.buffers[i].set(data[i].subarray(0, head), this.startIndex);
+            this.buffers[i].set(data[i].subarray(head), 0);
         }
         this.startIndex =
             (this.startIndex + data[0].length) % this.buffers[0].length;
@@ -257,6 +269,7 @@ function romLoaded(rom, exports, canvasContext, imageData) {
         init: exports.init,
         copy_save: exports.copy_save,
         main_loop: exports.main_loop,
+        set_rate_adjustment: exports.set_rate_adjustment,
         audio_processor: new ArbitraryAudioProcessor(
                 AUDIO_FRAMES_PER_SEC,
                 INTERNAL_AUDIO_FRAMES_PER_SEC,
@@ -298,11 +311,11 @@ function romLoaded(rom, exports, canvasContext, imageData) {
     }
     let saveHeap = Emu.alloc(save);
 
-    let screenHeap = Emu.alloc(new Uint8Array(SCREEN_X * SCREEN_Y));
+    let screenHeap = Emu.alloc(new Uint8Array(SCREEN_X * SCREEN_Y * 4));
     // Sound data is interleaved in the emulator
     //    sound = [left, right, left, right, ...]
     // for a frame of execution
-    let soundHeap = Emu.alloc(new Int16Array(AUDIO_FRAMES_PER_SEC * 2));
+    let soundHeap = Emu.alloc(new Int16Array(SOUND_BUFFER_SIZE));
     let gamepadHeap = Emu.alloc(new Uint8Array(8));
 
     Emu.init(romHeap.ptr, romHeap.size, saveHeap.ptr, screenHeap.ptr,
@@ -369,7 +382,7 @@ function romLoaded(rom, exports, canvasContext, imageData) {
 
     function mainLoop() {
         if (running) {
-            Emu.main_loop();
+            let soundCount = Emu.main_loop();
 
             let screen = Emu.view_u8(screenHeap);
             let img = imageData.data;
@@ -380,7 +393,11 @@ function romLoaded(rom, exports, canvasContext, imageData) {
 
             let sound = Emu.view_i16(soundHeap);
             Emu.audio_processor
-                .pushData(convertSoundToStereoF16(sound));
+                .pushData(convertSoundToStereoF16(sound, soundCount));
+            // Keep the audio buffer level steady, requestAnimationFrame
+            // doesn't run at exactly 60 fps.
+            Emu.set_rate_adjustment(
+                1 - Emu.audio_processor.level() * MAX_RATE_ADJUSTMENT);
 
             canvasContext.putImageData(imageData, 0, 0);
         }